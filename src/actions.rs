@@ -3,15 +3,29 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use crate::camera::{Bearing, LineEstimate, LineSettings, RangeSettings};
+use crate::odometry::Odometry;
+use crate::stall::StallSettings;
 use crate::control::{
     Robot,
     light::{LightColor},
-    movement::{Direction, Rotation},
+    movement::{Direction, Drive, Rotation},
     servo::Servo,
 };
 
 static ACTION_MOVE_SPEED: u8 = 20;
 
+// How far off straight ahead a target can be before approaching it turns
+// towards it rather than driving, in degrees
+static APPROACH_HEADING_TOLERANCE: f32 = 10.0;
+
+// How far off straight ahead movement can be before the sentry turns
+// towards it, in degrees
+static SENTRY_HEADING_TOLERANCE: f32 = 5.0;
+
+// How long each line following step drives for
+static LINE_STEP: Duration = Duration::from_millis(100);
+
 impl Robot {
     pub fn startup_action(&mut self) {
         println!("Executing startup action");
@@ -45,33 +59,31 @@ impl Robot {
         let _ = self.move_rotate(Rotation::Clockwise, 255, Duration::from_millis(500));
     }
 
-    pub fn green_action(&mut self, coordinate: (usize, usize), dimensions: (usize, usize)) {
-        // The camera seems to be flipped, so use the Y axis as horizontal direction
-        let direction = if coordinate.0 < (dimensions.0 / 2) {
+    pub fn green_action(&mut self, bearing: Bearing) {
+        let direction = if bearing.azimuth < 0.0 {
             Rotation::CounterClockwise
         } else {
             Rotation::Clockwise
         };
 
         println!(
-            "Executing blue action -- coordinate: ({}, {}), direction: {}",
-            coordinate.0, coordinate.1, direction
+            "Executing green action -- bearing: {}, direction: {}",
+            bearing, direction
         );
 
         _ = self.move_rotate(direction, ACTION_MOVE_SPEED, Duration::from_millis(250));
     }
 
-    pub fn blue_action(&mut self, coordinate: (usize, usize), dimensions: (usize, usize)) {
-        // The camera seems to be flipped, so use the Y axis as horizontal direction
-        let direction = if coordinate.0 < (dimensions.0 / 2) {
+    pub fn blue_action(&mut self, bearing: Bearing) {
+        let direction = if bearing.azimuth < 0.0 {
             Direction::Left
         } else {
             Direction::Right
         };
 
         println!(
-            "Executing blue action -- coordinate: ({}, {}), direction: {}",
-            coordinate.0, coordinate.1, direction
+            "Executing blue action -- bearing: {}, direction: {}",
+            bearing, direction
         );
 
         _ = self.move_direction(
@@ -80,4 +92,128 @@ impl Robot {
             Duration::from_millis(250),
         );
     }
+
+    // Slides sideways out of the way of an obstacle, away from the side it
+    // is on
+    pub fn avoid_action(&mut self, bearing: Bearing) {
+        let direction = if bearing.azimuth < 0.0 {
+            Direction::Right
+        } else {
+            Direction::Left
+        };
+
+        println!(
+            "Executing avoid action -- bearing: {}, direction: {}",
+            bearing, direction
+        );
+
+        _ = self.move_direction(
+            direction,
+            ACTION_MOVE_SPEED + 30,
+            Duration::from_millis(250),
+        );
+    }
+
+    // Turns to face the target, timed by how fast the robot has been seen to
+    // turn, then drives towards or away from it until it
+    // is at the stop range
+    pub fn approach_action(
+        &mut self,
+        bearing: Bearing,
+        distance: f32,
+        range: &RangeSettings,
+        odometry: &Odometry,
+    ) {
+        if bearing.azimuth.abs() > APPROACH_HEADING_TOLERANCE {
+            let rotation = if bearing.azimuth < 0.0 {
+                Rotation::CounterClockwise
+            } else {
+                Rotation::Clockwise
+            };
+
+            println!(
+                "Executing approach action -- bearing: {}, turning: {}",
+                bearing, rotation
+            );
+
+            let time = odometry.turn_time(bearing.azimuth, ACTION_MOVE_SPEED);
+            _ = self.move_rotate(rotation, ACTION_MOVE_SPEED, time);
+            return;
+        }
+
+        let direction = if distance > range.stop_range + range.tolerance {
+            Direction::Forward
+        } else if distance < range.stop_range - range.tolerance {
+            Direction::Backward
+        } else {
+            println!("Executing approach action -- {:.0} cm away, stopping", distance);
+            _ = self.stop();
+            return;
+        };
+
+        println!(
+            "Executing approach action -- {:.0} cm away, direction: {}",
+            distance, direction
+        );
+
+        _ = self.move_direction(direction, ACTION_MOVE_SPEED + 30, Duration::from_millis(250));
+    }
+
+    // Turns along the line and slides sideways back over it while driving
+    // forward, slowing down where it bends
+    pub fn follow_line_action(&mut self, line: &LineEstimate, settings: &LineSettings) {
+        let turn = (line.angle / settings.turn_angle).clamp(-1.0, 1.0);
+        let strafe = (line.offset * settings.strafe_gain).clamp(-1.0, 1.0);
+
+        let range = f32::from(settings.speed.saturating_sub(settings.min_speed));
+        let slowdown = 1.0 + settings.curvature_slowdown * line.curvature.abs();
+        let speed = settings.min_speed.min(settings.speed) + (range / slowdown) as u8;
+
+        println!(
+            "Executing follow line action -- turn: {:.2}, strafe: {:.2}, speed: {}",
+            turn, strafe, speed
+        );
+
+        _ = self.move_mecanum(1.0, strafe, turn, speed, LINE_STEP);
+    }
+
+    // Turns towards movement, timed by how fast the robot has been seen to
+    // turn. The background has to be learned again after every turn
+    pub fn sentry_action(&mut self, bearing: Bearing, odometry: &Odometry) {
+        if bearing.azimuth.abs() <= SENTRY_HEADING_TOLERANCE {
+            println!("Executing sentry action -- facing movement at {}", bearing);
+            return;
+        }
+
+        let rotation = if bearing.azimuth < 0.0 {
+            Rotation::CounterClockwise
+        } else {
+            Rotation::Clockwise
+        };
+
+        println!(
+            "Executing sentry action -- movement at {}, turning: {}",
+            bearing, rotation
+        );
+
+        let time = odometry.turn_time(bearing.azimuth, ACTION_MOVE_SPEED + 30);
+        _ = self.move_rotate(rotation, ACTION_MOVE_SPEED + 30, time);
+    }
+
+    // Shows the error light and backs away the way the stalled drive came
+    pub fn stall_action(&mut self, drive: Drive, settings: &StallSettings) {
+        println!(
+            "Executing stall action -- stalled driving ({:.1}, {:.1}, {:.1}), backing off",
+            drive.forward, drive.strafe, drive.turn
+        );
+
+        _ = self.set_all_lights(LightColor::purple());
+        _ = self.move_mecanum(
+            -drive.forward,
+            -drive.strafe,
+            -drive.turn,
+            settings.backoff_speed,
+            settings.backoff_time,
+        );
+    }
 }
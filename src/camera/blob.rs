@@ -0,0 +1,286 @@
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+use crate::camera::color::{ColorClasses, NO_CLASS};
+use crate::camera::shape::{Outline, Shape, describe};
+
+// A connected region of pixels of one class
+#[derive(Clone, Copy)]
+pub struct Blob {
+    pub class: u8,
+    pub area: usize,
+    // Top left and bottom right corners, both inclusive
+    pub bounding_box: ((usize, usize), (usize, usize)),
+    pub centroid: (usize, usize),
+    // Only worked out for blobs of color classes
+    pub outline: Outline,
+}
+
+impl Blob {
+    pub fn shape(&self) -> Shape {
+        self.outline.shape
+    }
+
+    pub fn width(&self) -> usize {
+        self.bounding_box.1.0 - self.bounding_box.0.0 + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.bounding_box.1.1 - self.bounding_box.0.1 + 1
+    }
+
+    // Middle of the bottom edge, where an object standing on the floor
+    // touches it. Continuous, so the middle of the bottom row of pixels
+    pub fn bottom_center(&self) -> (f32, f32) {
+        (
+            (self.bounding_box.0.0 + self.bounding_box.1.0 + 1) as f32 / 2.0,
+            (self.bounding_box.1.1 + 1) as f32,
+        )
+    }
+
+    // Width over height
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width() as f32 / self.height() as f32
+    }
+
+    // Share of the bounding box covered by the blob
+    pub fn fill_ratio(&self) -> f32 {
+        self.area as f32 / (self.width() * self.height()) as f32
+    }
+
+    // Whether the bounding box comes within `margin` pixels of the edge of
+    // the frame, where the blob may be cut off
+    pub fn touches_border(&self, dimensions: (usize, usize), margin: usize) -> bool {
+        let ((left, top), (right, bottom)) = self.bounding_box;
+        left <= margin
+            || top <= margin
+            || right + margin + 1 >= dimensions.0
+            || bottom + margin + 1 >= dimensions.1
+    }
+}
+
+// Which blob of a class actions should go after
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlobTarget {
+    Largest,
+    // The blob reaching lowest in the frame, which for a camera looking
+    // down at the floor is the one closest to the robot
+    Nearest,
+}
+
+impl FromStr for BlobTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest" => Ok(BlobTarget::Largest),
+            "nearest" => Ok(BlobTarget::Nearest),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BlobTarget {
+    // Higher is a better target
+    pub fn rank(self, blob: &Blob) -> (usize, usize) {
+        match self {
+            BlobTarget::Largest => (blob.area, 0),
+            BlobTarget::Nearest => (blob.bounding_box.1.1, blob.area),
+        }
+    }
+
+    pub fn pick<'a>(self, blobs: impl Iterator<Item = &'a Blob>) -> Option<&'a Blob> {
+        blobs.max_by_key(|b| self.rank(b))
+    }
+}
+
+// Running totals for one blob while the mask is scanned
+struct BlobTotals {
+    class: u8,
+    // The first pixel scanned, the top left one
+    start: (usize, usize),
+    area: usize,
+    min: (usize, usize),
+    max: (usize, usize),
+    sum: (u64, u64),
+}
+
+impl BlobTotals {
+    fn new(class: u8, x: usize, y: usize) -> Self {
+        Self {
+            class,
+            start: (x, y),
+            area: 0,
+            min: (x, y),
+            max: (x, y),
+            sum: (0, 0),
+        }
+    }
+
+    fn add(&mut self, x: usize, y: usize) {
+        self.area += 1;
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+        self.sum = (self.sum.0 + x as u64, self.sum.1 + y as u64);
+    }
+
+    // In full resolution pixels, for a mask downsampled by `scale`
+    fn blob(&self, scale: usize) -> Blob {
+        let area = self.area as u64;
+
+        Blob {
+            class: self.class,
+            area: self.area * scale * scale,
+            bounding_box: (
+                (self.min.0 * scale, self.min.1 * scale),
+                (
+                    self.max.0 * scale + scale - 1,
+                    self.max.1 * scale + scale - 1,
+                ),
+            ),
+            centroid: (
+                (self.sum.0 / area) as usize * scale + scale / 2,
+                (self.sum.1 / area) as usize * scale + scale / 2,
+            ),
+            outline: Outline::default(),
+        }
+    }
+}
+
+fn find_root(parents: &mut [u32], mut label: u32) -> u32 {
+    while parents[label as usize] != label {
+        // Halve the path on the way up so later lookups are shorter
+        let parent = parents[label as usize];
+        parents[label as usize] = parents[parent as usize];
+        label = parent;
+    }
+
+    label
+}
+
+fn merge(parents: &mut [u32], a: u32, b: u32) {
+    let a = find_root(parents, a);
+    let b = find_root(parents, b);
+
+    // Keep the lower label as the root so roots are found in scan order
+    if a < b {
+        parents[b as usize] = a;
+    } else if b < a {
+        parents[a as usize] = b;
+    }
+}
+
+// Splits the mask into 8-connected regions of the same value, ignoring
+// NO_CLASS, and totals them up in scan order
+fn label_regions(mask: &[u8], dimensions: (usize, usize)) -> Vec<BlobTotals> {
+    let (width, height) = dimensions;
+
+    // Label 0 is background, every other label starts as its own root
+    let mut labels = vec![0u32; mask.len()];
+    let mut parents = vec![0u32];
+
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            let class = mask[index];
+            if class == NO_CLASS {
+                continue;
+            }
+
+            // The neighbours that have already been scanned: left, then the
+            // three above
+            let mut neighbours = [None; 4];
+            if x > 0 {
+                neighbours[0] = Some(index - 1);
+            }
+            if y > 0 {
+                let above = index - width;
+                if x > 0 {
+                    neighbours[1] = Some(above - 1);
+                }
+                neighbours[2] = Some(above);
+                if x + 1 < width {
+                    neighbours[3] = Some(above + 1);
+                }
+            }
+
+            let mut label = 0;
+            for neighbour in neighbours.into_iter().flatten() {
+                if mask[neighbour] != class {
+                    continue;
+                }
+
+                let other = labels[neighbour];
+                if label == 0 {
+                    label = other;
+                } else if other != label {
+                    merge(&mut parents, label, other);
+                }
+            }
+
+            if label == 0 {
+                label = parents.len() as u32;
+                parents.push(label);
+            }
+
+            labels[index] = label;
+        }
+    }
+
+    // Index into totals for each root label
+    let mut slots = vec![u32::MAX; parents.len()];
+    let mut totals: Vec<BlobTotals> = Vec::new();
+
+    for (index, &label) in labels.iter().enumerate() {
+        if label == 0 {
+            continue;
+        }
+
+        let (x, y) = (index % width, index / width);
+        let root = find_root(&mut parents, label) as usize;
+        if slots[root] == u32::MAX {
+            slots[root] = totals.len() as u32;
+            totals.push(BlobTotals::new(mask[index], x, y));
+        }
+
+        totals[slots[root] as usize].add(x, y);
+    }
+
+    totals
+}
+
+// The regions of the mask as blobs. The mask is `dimensions` in size and
+// downsampled by `scale`, the regions are measured at full resolution. In
+// scan order
+pub fn find_regions(mask: &[u8], dimensions: (usize, usize), scale: usize) -> Vec<Blob> {
+    label_regions(mask, dimensions)
+        .iter()
+        .map(|t| t.blob(scale))
+        .collect()
+}
+
+// Regions of every class with their shapes, dropping any smaller than their
+// class's minimum blob area. Largest first
+pub fn find_blobs(
+    mask: &[u8],
+    dimensions: (usize, usize),
+    scale: usize,
+    classes: &ColorClasses,
+) -> Vec<Blob> {
+    let mut blobs: Vec<Blob> = label_regions(mask, dimensions)
+        .iter()
+        .map(|t| (t, t.blob(scale)))
+        .filter(|(_, b)| {
+            classes
+                .get(b.class)
+                .is_some_and(|c| b.area >= c.thresholds.blob_area)
+        })
+        .map(|(t, mut b)| {
+            b.outline = describe(mask, dimensions, t.class, t.start);
+            b
+        })
+        .collect();
+
+    blobs.sort_by_key(|b| Reverse(b.area));
+    blobs
+}
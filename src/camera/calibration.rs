@@ -0,0 +1,269 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::camera::color::{ColorClass, ColorClasses, DARK_THRESHOLD, YuvChroma};
+use crate::camera::{
+    CameraVideoStream, ColorTable, Frame, FrameGeometry, FrameSampler, MaskFilter,
+};
+use crate::config::{Config, config_path};
+
+const CALIBRATION_FILE: &str = "colors.conf";
+
+// Frames sampled for each color
+const CALIBRATION_FRAMES: usize = 20;
+
+// Corrupt frames tolerated for each color before the camera is given up on
+const MAX_CORRUPT_FRAMES: usize = 20;
+
+// Time to get the target in place before sampling starts
+const CALIBRATION_DELAY: Duration = Duration::from_secs(3);
+
+// Only the middle of the frame is sampled, this fraction of the width and
+// height, so the target doesn't have to fill the whole view
+const SAMPLE_REGION: f32 = 0.2;
+
+// Sample every nth pixel of the region in each direction
+const SAMPLE_STEP: usize = 4;
+
+// Fraction of samples closest to the median that are kept, the rest are
+// treated as background or glare around the target
+const SAMPLE_KEEP: f32 = 0.75;
+
+// Chroma distribution of one color, as seen by the camera
+#[derive(Clone, Copy)]
+pub struct ChromaModel {
+    pub mean: (f32, f32),
+    // (uu, uv, vv)
+    pub covariance: (f32, f32, f32),
+    pub samples: usize,
+}
+
+impl ChromaModel {
+    // Trimmed mean and covariance around the median, so stray pixels around
+    // the target don't drag the estimate away from it
+    fn estimate(samples: &[(f32, f32)]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let median = (
+            Self::median(samples.iter().map(|s| s.0).collect()),
+            Self::median(samples.iter().map(|s| s.1).collect()),
+        );
+
+        let mut by_distance: Vec<(f32, (f32, f32))> = samples
+            .iter()
+            .map(|s| ((s.0 - median.0).powi(2) + (s.1 - median.1).powi(2), *s))
+            .collect();
+        by_distance.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let keep = ((samples.len() as f32 * SAMPLE_KEEP) as usize).max(1);
+        let kept: Vec<(f32, f32)> = by_distance[..keep].iter().map(|(_, s)| *s).collect();
+
+        let count = kept.len() as f32;
+        let mean = (
+            kept.iter().map(|s| s.0).sum::<f32>() / count,
+            kept.iter().map(|s| s.1).sum::<f32>() / count,
+        );
+
+        let mut covariance = (0.0, 0.0, 0.0);
+        for s in &kept {
+            let d = (s.0 - mean.0, s.1 - mean.1);
+            covariance.0 += d.0 * d.0;
+            covariance.1 += d.0 * d.1;
+            covariance.2 += d.1 * d.1;
+        }
+
+        Some(Self {
+            mean,
+            covariance: (
+                covariance.0 / count,
+                covariance.1 / count,
+                covariance.2 / count,
+            ),
+            samples: kept.len(),
+        })
+    }
+
+    fn median(mut values: Vec<f32>) -> f32 {
+        values.sort_by(|a, b| a.total_cmp(b));
+        values[values.len() / 2]
+    }
+
+    pub fn anchor(&self) -> YuvChroma {
+        YuvChroma::new(
+            self.mean.0.round().clamp(0.0, 255.0) as u8,
+            self.mean.1.round().clamp(0.0, 255.0) as u8,
+        )
+    }
+}
+
+// Learned chroma for each color class, by name. Applied on top of the
+// configured classes at startup
+#[derive(Default)]
+pub struct ColorCalibration {
+    models: Vec<(String, ChromaModel)>,
+}
+
+impl ColorCalibration {
+    pub fn new() -> Self {
+        Self { models: Vec::new() }
+    }
+
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let mut calibration = Self::new();
+        for name in config.section_names() {
+            let model = ChromaModel {
+                mean: (config.parse_value(name, "u")?, config.parse_value(name, "v")?),
+                covariance: (
+                    config.parse_value(name, "uu")?,
+                    config.parse_value(name, "uv")?,
+                    config.parse_value(name, "vv")?,
+                ),
+                samples: config.parse_value(name, "samples")?,
+            };
+
+            calibration.set_model(name, model);
+        }
+
+        Ok(calibration)
+    }
+
+    // A missing file just means nothing has been calibrated yet
+    pub fn load_or_default() -> Self {
+        Config::load_or_default(CALIBRATION_FILE, "color calibration", Self::parse)
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut config = Config::new();
+        for (name, model) in &self.models {
+            config.set(name, "u", model.mean.0);
+            config.set(name, "v", model.mean.1);
+            config.set(name, "uu", model.covariance.0);
+            config.set(name, "uv", model.covariance.1);
+            config.set(name, "vv", model.covariance.2);
+            config.set(name, "samples", model.samples);
+        }
+
+        let path = config_path(CALIBRATION_FILE);
+        config.save(&path)?;
+        println!("Saved the color calibration to {}", path.display());
+
+        Ok(())
+    }
+
+    pub fn model(&self, name: &str) -> Option<&ChromaModel> {
+        self.models.iter().find(|(n, _)| n == name).map(|(_, m)| m)
+    }
+
+    fn set_model(&mut self, name: &str, model: ChromaModel) {
+        match self.models.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = model,
+            None => self.models.push((name.to_string(), model)),
+        }
+    }
+}
+
+// Chroma of the non-dark pixels in the middle of the frame
+fn sample_region(frame: &Frame, samples: &mut Vec<(f32, f32)>) {
+    let (width, height) = frame.dimensions();
+    let region = (
+        (width as f32 * SAMPLE_REGION) as usize,
+        (height as f32 * SAMPLE_REGION) as usize,
+    );
+    let start = ((width - region.0) / 2, (height - region.1) / 2);
+
+    for y in (start.1..start.1 + region.1).step_by(SAMPLE_STEP) {
+        for x in (start.0..start.0 + region.0).step_by(SAMPLE_STEP) {
+            let (luma, u, v) = frame.pixel(x, y);
+            if luma >= DARK_THRESHOLD {
+                samples.push((f32::from(u), f32::from(v)));
+            }
+        }
+    }
+}
+
+// Walks through every color class, asking for its target to be held in the
+// middle of the camera view. `show` is called before each class so the robot
+// can signal which target it wants
+pub fn calibrate_colors(
+    camera_stream: &mut CameraVideoStream,
+    mut show: impl FnMut(&ColorClass),
+) -> std::io::Result<()> {
+    let classes = Arc::new(ColorClasses::load());
+
+    // Sampled frames are only used for their raw pixels, so the
+    // classification doesn't matter
+    let color_table = ColorTable::new(classes.clone());
+    let mask_filter = MaskFilter::default();
+
+    // Only the middle of the frame is sampled, so orientation and lens
+    // distortion don't matter either
+    let mut sampler = FrameSampler::new(FrameGeometry::default());
+
+    // Keep calibrations for classes that aren't configured right now
+    let mut calibration = ColorCalibration::load_or_default();
+    for class in classes.iter() {
+        show(class);
+        println!(
+            "Hold the {} target so it fills the middle of the camera view, sampling in {} seconds",
+            class.name,
+            CALIBRATION_DELAY.as_secs()
+        );
+        sleep(CALIBRATION_DELAY);
+
+        // Throw away whatever was sitting in the driver's buffers
+        for _ in 0..2 {
+            camera_stream.get_next_jpeg()?;
+        }
+
+        let mut samples = Vec::new();
+        let mut frames = 0;
+        let mut corrupt = 0;
+        while frames < CALIBRATION_FRAMES {
+            let jpeg = camera_stream.get_next_jpeg()?;
+            let decoded =
+                Frame::decode(&jpeg, &color_table, &mask_filter, &mut sampler, None, None, (0, 0));
+            let frame = match decoded {
+                Ok(frame) => frame,
+                Err(e) => {
+                    println!("Skipping a corrupt frame: {}", e);
+                    corrupt += 1;
+                    if corrupt >= MAX_CORRUPT_FRAMES {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("the camera returned {} corrupt frames", corrupt),
+                        ));
+                    }
+                    continue;
+                }
+            };
+
+            sample_region(&frame, &mut samples);
+            frames += 1;
+        }
+
+        let model = ChromaModel::estimate(&samples).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("The {} target was too dark to sample", class.name),
+            )
+        })?;
+
+        println!(
+            "{}: chroma ({:.1}, {:.1}), spread ({:.1}, {:.1}) from {} samples",
+            class.name,
+            model.mean.0,
+            model.mean.1,
+            model.covariance.0.sqrt(),
+            model.covariance.2.sqrt(),
+            model.samples
+        );
+
+        calibration.set_model(&class.name, model);
+    }
+
+    calibration.save()
+}
@@ -0,0 +1,424 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use crate::camera::calibration::ColorCalibration;
+use crate::camera::region::{ChromaRegion, ClassifierMode, chroma_hue, chroma_range};
+use crate::config::Config;
+
+const CLASSES_FILE: &str = "classes.conf";
+
+// Pixels darker than this are too noisy to classify
+pub(super) const DARK_THRESHOLD: u8 = 16;
+
+// Mask value for pixels that don't belong to any class
+pub const NO_CLASS: u8 = u8::MAX;
+
+#[derive(Clone, Copy)]
+pub struct YuvChroma {
+    u: u8,
+    v: u8,
+}
+
+impl YuvChroma {
+    pub const fn new(u: u8, v: u8) -> Self {
+        Self { u, v }
+    }
+
+    fn distance(&self, other: &YuvChroma) -> u32 {
+        let dx = i32::from(self.u) - i32::from(other.u);
+        let dy = i32::from(self.v) - i32::from(other.v);
+
+        // Powers of two are always positive
+        u32::try_from(dx.pow(2) + dy.pow(2)).unwrap()
+    }
+}
+
+// Uncolored pixels, anything closer to this than to every class is left
+// unclassified
+const NEUTRAL_CHROMA: YuvChroma = YuvChroma::new(128, 128);
+
+#[derive(Clone)]
+pub struct ColorClass {
+    pub name: String,
+    pub chroma: YuvChroma,
+    // Inclusive luma range, pixels outside it are left unclassified
+    pub luma: (u8, u8),
+    // What the robot's lights show while this class is seen
+    pub light: (u8, u8, u8),
+    pub thresholds: Thresholds,
+    // Used by the elliptical and HSV classifiers
+    pub region: ChromaRegion,
+}
+
+// Minimums a class has to reach in a frame before it counts as detected
+#[derive(Clone, Copy)]
+pub struct Thresholds {
+    pub pixels: usize,
+    // Share of the frame covered by the class
+    pub fraction: f32,
+    // Pixel count over the area of the ellipse two standard deviations out
+    // from the class's pixels, see ColorStats::compactness
+    pub compactness: f32,
+    // Average margin of the class's pixels, see ColorTable
+    pub margin: f32,
+    // Combined score, see Detection
+    pub confidence: f32,
+    // Connected regions with fewer pixels than this are treated as noise
+    pub blob_area: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            pixels: 1,
+            fraction: 0.0,
+            compactness: 0.0,
+            margin: 0.0,
+            confidence: 0.1,
+            blob_area: 64,
+        }
+    }
+}
+
+impl ColorClass {
+    fn new(name: &str, chroma: YuvChroma, light: (u8, u8, u8)) -> Self {
+        Self {
+            name: name.to_string(),
+            chroma,
+            luma: (DARK_THRESHOLD, u8::MAX),
+            light,
+            thresholds: Thresholds::default(),
+            region: ChromaRegion::around(chroma.u, chroma.v),
+        }
+    }
+}
+
+// The set of colors the robot looks for, in the order they were configured.
+// A class is referred to by its index in this set
+pub struct ColorClasses {
+    mode: ClassifierMode,
+    classes: Vec<ColorClass>,
+}
+
+impl Default for ColorClasses {
+    fn default() -> Self {
+        Self {
+            mode: ClassifierMode::Nearest,
+            classes: vec![
+                ColorClass::new("red", YuvChroma::new(60, 210), (255, 0, 0)),
+                ColorClass::new("green", YuvChroma::new(109, 105), (0, 255, 0)),
+                ColorClass::new("blue", YuvChroma::new(136, 80), (0, 0, 255)),
+            ],
+        }
+    }
+}
+
+impl ColorClasses {
+    // Classes come from classes.conf, one section per class:
+    //
+    // [yellow]
+    // u = 40
+    // v = 150
+    // luma_min = 16
+    // luma_max = 255
+    // light = 255, 255, 0
+    // min_pixels = 200
+    // min_fraction = 0.001
+    // min_compactness = 0.2
+    // min_margin = 4
+    // min_confidence = 0.1
+    // min_blob_area = 64
+    // uu = 144
+    // uv = 0
+    // vv = 144
+    // max_distance = 3
+    // hue_min = 30
+    // hue_max = 90
+    // saturation_min = 0.25
+    //
+    // The classifier is picked at the top of the file, before any class:
+    //
+    // classifier = elliptical
+    //
+    // `nearest` (the default) assigns every colored pixel to the closest
+    // anchor. `elliptical` measures distance against each class's covariance
+    // (uu, uv, vv, or the calibrated spread) and rejects pixels further than
+    // max_distance deviations from every class. `hsv` matches pixels whose hue
+    // falls in the class's band, in degrees, with at least saturation_min
+    // saturation.
+    //
+    // Everything but the chroma is optional. In hsv mode the chroma is only
+    // used to centre the default hue band, so it can be left out when the band
+    // is given
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let mode = config.parse_or("", "classifier", ClassifierMode::Nearest)?;
+
+        let mut classes = Vec::new();
+        for name in config.section_names() {
+            let chroma = if mode == ClassifierMode::Hsv {
+                YuvChroma::new(config.parse_or(name, "u", 128)?, config.parse_or(name, "v", 128)?)
+            } else {
+                YuvChroma::new(config.parse_value(name, "u")?, config.parse_value(name, "v")?)
+            };
+
+            let mut class = ColorClass::new(name, chroma, (255, 255, 255));
+
+            class.luma = (
+                config.parse_or(name, "luma_min", class.luma.0)?,
+                config.parse_or(name, "luma_max", class.luma.1)?,
+            );
+
+            let defaults = class.thresholds;
+            class.thresholds = Thresholds {
+                pixels: config.parse_or(name, "min_pixels", defaults.pixels)?,
+                fraction: config.parse_or(name, "min_fraction", defaults.fraction)?,
+                compactness: config.parse_or(name, "min_compactness", defaults.compactness)?,
+                margin: config.parse_or(name, "min_margin", defaults.margin)?,
+                confidence: config.parse_or(name, "min_confidence", defaults.confidence)?,
+                blob_area: config.parse_or(name, "min_blob_area", defaults.blob_area)?,
+            };
+
+            let region = class.region;
+            class.region.set_covariance((
+                config.parse_or(name, "uu", region.covariance.0)?,
+                config.parse_or(name, "uv", region.covariance.1)?,
+                config.parse_or(name, "vv", region.covariance.2)?,
+            ));
+            class.region.max_distance = config.parse_or(name, "max_distance", region.max_distance)?;
+            class.region.hue = (
+                config.parse_or(name, "hue_min", region.hue.0)?,
+                config.parse_or(name, "hue_max", region.hue.1)?,
+            );
+            class.region.saturation_min =
+                config.parse_or(name, "saturation_min", region.saturation_min)?;
+
+            if let Some(light) = config.get(name, "light") {
+                class.light = parse_rgb(light).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid light `{}` for class {}", light, name),
+                    )
+                })?;
+            }
+
+            classes.push(class);
+        }
+
+        if classes.len() >= usize::from(NO_CLASS) {
+            return Err(Error::new(ErrorKind::InvalidData, "too many color classes"));
+        }
+
+        Ok(Self { mode, classes })
+    }
+
+    // Falls back to red, green and blue if nothing is configured, then
+    // applies any calibrated chroma and spread on top
+    pub fn load() -> Self {
+        let mut classes = Config::load_or_default(CLASSES_FILE, "color classes", Self::parse);
+
+        let calibration = ColorCalibration::load_or_default();
+        for class in &mut classes.classes {
+            if let Some(model) = calibration.model(&class.name) {
+                class.chroma = model.anchor();
+                class.region.set_covariance(model.covariance);
+            }
+        }
+
+        classes
+    }
+
+    pub fn mode(&self) -> ClassifierMode {
+        self.mode
+    }
+
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    // The id of a class by name
+    pub fn find(&self, name: &str) -> Option<u8> {
+        self.classes.iter().position(|c| c.name == name).map(|id| id as u8)
+    }
+
+    pub fn get(&self, id: u8) -> Option<&ColorClass> {
+        self.classes.get(usize::from(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ColorClass> {
+        self.classes.iter()
+    }
+}
+
+fn parse_rgb(text: &str) -> Option<(u8, u8, u8)> {
+    let mut parts = text.split(',').map(|p| p.trim().parse::<u8>());
+    let rgb = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(rgb)
+}
+
+// Every possible (u, v) chroma mapped to its closest class, so classifying a
+// pixel is a single lookup. Rebuild it whenever the classes change
+pub struct ColorTable {
+    classes: Arc<ColorClasses>,
+    chroma: Vec<u8>,
+    // How clearly each chroma belongs to its class, a measure of how
+    // ambiguous the pixel is. In chroma units over the runner up for the
+    // nearest classifier, eighths of a deviation over the runner up or the
+    // rejection radius for the elliptical one, and degrees inside the band
+    // for hsv
+    margins: Vec<u8>,
+    // Only built in hsv mode
+    hsv: Option<HsvTable>,
+}
+
+// Saturation depends on the pixel's luma, so it can't be looked up directly
+struct HsvTable {
+    // The HSV chroma and largest channel offset for every chroma
+    ranges: Vec<(u8, i16)>,
+    // Each class's minimum saturation, out of 255
+    saturation: Vec<u16>,
+}
+
+impl ColorTable {
+    pub fn new(classes: Arc<ColorClasses>) -> Self {
+        let mut chroma = Vec::with_capacity(256 * 256);
+        let mut margins = Vec::with_capacity(256 * 256);
+        for u in 0..=u8::MAX {
+            for v in 0..=u8::MAX {
+                let (class, margin) = match classes.mode() {
+                    ClassifierMode::Nearest => Self::closest(&classes, &YuvChroma::new(u, v)),
+                    ClassifierMode::Elliptical => Self::closest_elliptical(&classes, u, v),
+                    ClassifierMode::Hsv => Self::closest_hue(&classes, u, v),
+                };
+                chroma.push(class);
+                margins.push(margin);
+            }
+        }
+
+        let hsv = (classes.mode() == ClassifierMode::Hsv).then(|| {
+            let mut ranges = Vec::with_capacity(256 * 256);
+            for u in 0..=u8::MAX {
+                for v in 0..=u8::MAX {
+                    ranges.push(chroma_range(u, v));
+                }
+            }
+
+            let saturation = classes
+                .iter()
+                .map(|c| (c.region.saturation_min.clamp(0.0, 1.0) * 255.0).round() as u16)
+                .collect();
+
+            HsvTable { ranges, saturation }
+        });
+
+        Self {
+            classes,
+            chroma,
+            margins,
+            hsv,
+        }
+    }
+
+    // The closest class and its margin over the runner up
+    fn closest(classes: &ColorClasses, input: &YuvChroma) -> (u8, u8) {
+        let mut closest = NO_CLASS;
+        let mut min = NEUTRAL_CHROMA.distance(input);
+        let mut second = u32::MAX;
+        for (id, class) in classes.iter().enumerate() {
+            let distance = class.chroma.distance(input);
+            if distance < min {
+                second = min;
+                min = distance;
+                closest = id as u8;
+            } else if distance < second {
+                second = distance;
+            }
+        }
+
+        let margin = (second as f32).sqrt() - (min as f32).sqrt();
+
+        (closest, margin.round().min(255.0) as u8)
+    }
+
+    // The class with the smallest Mahalanobis distance, as long as it's
+    // within that class's rejection radius. There's no neutral anchor, grey
+    // is simply outside every ellipse
+    fn closest_elliptical(classes: &ColorClasses, u: u8, v: u8) -> (u8, u8) {
+        let mut closest = NO_CLASS;
+        let mut min = f32::MAX;
+        let mut second = f32::MAX;
+        for (id, class) in classes.iter().enumerate() {
+            let distance = class.region.mahalanobis((class.chroma.u, class.chroma.v), u, v);
+            if distance > class.region.max_distance {
+                continue;
+            }
+
+            if distance < min {
+                second = min;
+                min = distance;
+                closest = id as u8;
+            } else if distance < second {
+                second = distance;
+            }
+        }
+
+        let Some(class) = classes.get(closest) else {
+            return (NO_CLASS, 0);
+        };
+
+        let margin = (second.min(class.region.max_distance) - min) * 8.0;
+
+        (closest, margin.round().min(255.0) as u8)
+    }
+
+    // The class whose hue band the chroma sits deepest inside. Saturation is
+    // checked per pixel
+    fn closest_hue(classes: &ColorClasses, u: u8, v: u8) -> (u8, u8) {
+        let hue = chroma_hue(u, v);
+
+        let mut closest = NO_CLASS;
+        let mut best = 0.0;
+        for (id, class) in classes.iter().enumerate() {
+            let margin = class.region.hue_margin(hue);
+            if margin >= 0.0 && (closest == NO_CLASS || margin > best) {
+                best = margin;
+                closest = id as u8;
+            }
+        }
+
+        (closest, best.round().min(255.0) as u8)
+    }
+
+    pub fn classes(&self) -> &Arc<ColorClasses> {
+        &self.classes
+    }
+
+    // Returns the class id, or NO_CLASS, and the chroma margin
+    pub fn classify(&self, y: u8, u: u8, v: u8) -> (u8, u8) {
+        let index = (usize::from(u) << 8) | usize::from(v);
+        let id = self.chroma[index];
+
+        let Some(class) = self.classes.get(id) else {
+            return (NO_CLASS, 0);
+        };
+
+        if y < class.luma.0 || y > class.luma.1 {
+            return (NO_CLASS, 0);
+        }
+
+        // Saturation is chroma / value, compared without dividing
+        if let Some(hsv) = &self.hsv {
+            let (range, offset) = hsv.ranges[index];
+            let value = (i16::from(y) + offset).clamp(1, 255) as u16;
+            if u16::from(range) * 255 < hsv.saturation[usize::from(id)] * value {
+                return (NO_CLASS, 0);
+            }
+        }
+
+        (id, self.margins[index])
+    }
+}
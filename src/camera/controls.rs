@@ -0,0 +1,200 @@
+use core::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use v4l::Control;
+use v4l::control::{Description, Flags, Value};
+use v4l::v4l_sys::{
+    V4L2_CID_AUTO_WHITE_BALANCE, V4L2_CID_BRIGHTNESS, V4L2_CID_EXPOSURE_ABSOLUTE,
+    V4L2_CID_EXPOSURE_AUTO, V4L2_CID_FOCUS_ABSOLUTE, V4L2_CID_FOCUS_AUTO, V4L2_CID_GAIN,
+    V4L2_CID_WHITE_BALANCE_TEMPERATURE,
+};
+
+use crate::camera::CameraVideoStream;
+use crate::config::{Config, config_path};
+
+// Values for V4L2_CID_EXPOSURE_AUTO
+pub const EXPOSURE_MANUAL: i64 = 1;
+pub const EXPOSURE_APERTURE_PRIORITY: i64 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CameraControl {
+    ExposureAuto,
+    Exposure,
+    Gain,
+    Brightness,
+    WhiteBalanceAuto,
+    WhiteBalance,
+    FocusAuto,
+    Focus,
+}
+
+impl CameraControl {
+    // Automatic modes come before their manual values, since most cameras
+    // ignore a manual value while the automatic mode is still on
+    pub fn values() -> [Self; 8] {
+        [
+            Self::ExposureAuto,
+            Self::Exposure,
+            Self::Gain,
+            Self::Brightness,
+            Self::WhiteBalanceAuto,
+            Self::WhiteBalance,
+            Self::FocusAuto,
+            Self::Focus,
+        ]
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            CameraControl::ExposureAuto => V4L2_CID_EXPOSURE_AUTO,
+            CameraControl::Exposure => V4L2_CID_EXPOSURE_ABSOLUTE,
+            CameraControl::Gain => V4L2_CID_GAIN,
+            CameraControl::Brightness => V4L2_CID_BRIGHTNESS,
+            CameraControl::WhiteBalanceAuto => V4L2_CID_AUTO_WHITE_BALANCE,
+            CameraControl::WhiteBalance => V4L2_CID_WHITE_BALANCE_TEMPERATURE,
+            CameraControl::FocusAuto => V4L2_CID_FOCUS_AUTO,
+            CameraControl::Focus => V4L2_CID_FOCUS_ABSOLUTE,
+        }
+    }
+
+    // Name used in profile files and on the command line
+    fn name(&self) -> &'static str {
+        match self {
+            CameraControl::ExposureAuto => "exposure_auto",
+            CameraControl::Exposure => "exposure",
+            CameraControl::Gain => "gain",
+            CameraControl::Brightness => "brightness",
+            CameraControl::WhiteBalanceAuto => "white_balance_auto",
+            CameraControl::WhiteBalance => "white_balance",
+            CameraControl::FocusAuto => "focus_auto",
+            CameraControl::Focus => "focus",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::values().into_iter().find(|c| c.name() == name)
+    }
+}
+
+impl fmt::Display for CameraControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+fn profile_path(name: &str) -> PathBuf {
+    config_path("camera").join(format!("{}.conf", name))
+}
+
+impl CameraVideoStream<'_> {
+    // Every control the device exposes, including ones without a
+    // CameraControl equivalent
+    pub fn list_controls(&self) -> std::io::Result<Vec<Description>> {
+        let controls = self.device.query_controls()?;
+
+        Ok(controls
+            .into_iter()
+            .filter(|c| !c.flags.contains(Flags::DISABLED))
+            .collect())
+    }
+
+    pub fn print_controls(&self) -> std::io::Result<()> {
+        for description in self.list_controls()? {
+            let current = match self.device.control(description.id) {
+                Ok(Control {
+                    value: Value::Integer(v),
+                    ..
+                }) => v.to_string(),
+                Ok(Control {
+                    value: Value::Boolean(v),
+                    ..
+                }) => v.to_string(),
+                _ => String::from("-"),
+            };
+
+            println!(
+                "{:<32} {:<10} min {:<6} max {:<6} step {:<4} default {:<6} current {}",
+                description.name,
+                description.typ,
+                description.minimum,
+                description.maximum,
+                description.step,
+                description.default,
+                current
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn get_control(&self, control: CameraControl) -> std::io::Result<i64> {
+        match self.device.control(control.id())?.value {
+            Value::Integer(v) => Ok(v),
+            Value::Boolean(v) => Ok(i64::from(v)),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} is not a numeric control", control),
+            )),
+        }
+    }
+
+    // Minimum and maximum, both inclusive
+    pub fn control_range(&self, control: CameraControl) -> std::io::Result<(i64, i64)> {
+        self.list_controls()?
+            .into_iter()
+            .find(|c| c.id == control.id())
+            .map(|c| (c.minimum, c.maximum))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::Unsupported,
+                    format!("The camera has no {} control", control),
+                )
+            })
+    }
+
+    pub fn set_control(&self, control: CameraControl, value: i64) -> std::io::Result<()> {
+        self.device.set_control(Control {
+            id: control.id(),
+            value: Value::Integer(value),
+        })
+    }
+
+    pub fn save_profile(&self, name: &str) -> std::io::Result<()> {
+        let mut profile = Config::new();
+        for control in CameraControl::values() {
+            // Not every camera has every control, only save the ones it has
+            if let Ok(value) = self.get_control(control) {
+                profile.set("", control.name(), value);
+            }
+        }
+
+        let path = profile_path(name);
+        profile.save(&path)?;
+        println!("Saved camera profile to {}", path.display());
+
+        Ok(())
+    }
+
+    pub fn load_profile(&self, name: &str) -> std::io::Result<()> {
+        let profile = Config::load(&profile_path(name))?;
+
+        for (key, value) in profile.entries("") {
+            let Some(control) = CameraControl::from_name(key) else {
+                println!("Ignoring unknown camera control {} in profile {}", key, name);
+                continue;
+            };
+
+            let Ok(value) = value.parse() else {
+                println!("Ignoring invalid value {} for {} in profile {}", value, key, name);
+                continue;
+            };
+
+            if let Err(e) = self.set_control(control, value) {
+                println!("Failed to set {} to {}: {}", control, value, e);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::Mutex;
+
+use crate::camera::controls::{CameraControl, EXPOSURE_MANUAL};
+use crate::camera::{CameraVideoStream, Frame};
+
+// Average luma the exposure controller aims for
+const TARGET_LUMA: f32 = 110.0;
+
+// Don't bother adjusting when within this fraction of the target
+const EXPOSURE_DEADBAND: f32 = 0.1;
+
+// Limits on a single adjustment, so one odd frame can't swing the exposure
+const MAX_EXPOSURE_STEP: f32 = 2.0;
+
+// Frames to wait after an adjustment before measuring again, the camera takes
+// a few frames to apply new values
+const EXPOSURE_SETTLE_FRAMES: u32 = 4;
+
+// Drives the manual exposure and gain towards a target brightness, so colors
+// look the same from room to room instead of depending on the camera's own
+// auto exposure
+pub struct AutoExposure {
+    settle_frames: u32,
+}
+
+impl AutoExposure {
+    pub fn new() -> Self {
+        Self { settle_frames: 0 }
+    }
+
+    // Returns the factor the exposure should be scaled by, if it needs to
+    // change at all
+    pub fn update(&mut self, frame: &Frame) -> Option<f32> {
+        if self.settle_frames > 0 {
+            self.settle_frames -= 1;
+            return None;
+        }
+
+        let luma = f32::from(frame.average_luma()).max(1.0);
+        let ratio = TARGET_LUMA / luma;
+        if (ratio - 1.0).abs() < EXPOSURE_DEADBAND {
+            return None;
+        }
+
+        self.settle_frames = EXPOSURE_SETTLE_FRAMES;
+
+        // Luma doesn't scale linearly with exposure once pixels start to
+        // clip, so only go part of the way there
+        Some(
+            ratio
+                .sqrt()
+                .clamp(1.0 / MAX_EXPOSURE_STEP, MAX_EXPOSURE_STEP),
+        )
+    }
+}
+
+// How quickly the white balance follows the scene, per frame
+const WHITE_BALANCE_SMOOTHING: f32 = 0.05;
+
+// The largest chroma correction, so a frame filled by one colored target
+// can't be "corrected" into grey
+const MAX_CHROMA_OFFSET: f32 = 24.0;
+
+// Gray world white balance: on average a scene should have neutral chroma, so
+// whatever offset the average has is the color cast of the lighting. The
+// offset is shared between the control thread, which updates it, and the
+// decode workers, which apply it
+pub struct WhiteBalance {
+    offset: Mutex<(f32, f32)>,
+}
+
+impl WhiteBalance {
+    pub fn new() -> Self {
+        Self {
+            offset: Mutex::new((0.0, 0.0)),
+        }
+    }
+
+    pub fn update(&self, frame: &Frame) {
+        let average = frame.average();
+        let target = (
+            (128.0 - f32::from(average.0)).clamp(-MAX_CHROMA_OFFSET, MAX_CHROMA_OFFSET),
+            (128.0 - f32::from(average.1)).clamp(-MAX_CHROMA_OFFSET, MAX_CHROMA_OFFSET),
+        );
+
+        let mut offset = self.offset.lock().unwrap();
+        offset.0 += (target.0 - offset.0) * WHITE_BALANCE_SMOOTHING;
+        offset.1 += (target.1 - offset.1) * WHITE_BALANCE_SMOOTHING;
+    }
+
+    pub fn offset(&self) -> (i16, i16) {
+        let offset = self.offset.lock().unwrap();
+        (offset.0.round() as i16, offset.1.round() as i16)
+    }
+}
+
+impl CameraVideoStream<'_> {
+    pub fn set_manual_exposure(&self) -> std::io::Result<()> {
+        self.set_control(CameraControl::ExposureAuto, EXPOSURE_MANUAL)
+    }
+
+    // Brightens using exposure before gain, since gain adds noise, and darkens
+    // by removing gain first for the same reason
+    pub fn scale_exposure(&self, ratio: f32) -> std::io::Result<()> {
+        let order = if ratio > 1.0 {
+            [CameraControl::Exposure, CameraControl::Gain]
+        } else {
+            [CameraControl::Gain, CameraControl::Exposure]
+        };
+
+        for control in order {
+            match self.scale_control(control, ratio) {
+                Ok(true) => break,
+                Ok(false) => (),
+                // Not every camera has a gain control
+                Err(_) if control == CameraControl::Gain => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns false if the control was already at its limit
+    fn scale_control(&self, control: CameraControl, ratio: f32) -> std::io::Result<bool> {
+        let (minimum, maximum) = self.control_range(control)?;
+        let current = self.get_control(control)?;
+
+        let mut wanted = (current as f32 * ratio).round() as i64;
+
+        // Small values would otherwise round back to where they started
+        if wanted == current {
+            wanted += if ratio > 1.0 { 1 } else { -1 };
+        }
+
+        let wanted = wanted.clamp(minimum, maximum);
+        if wanted == current {
+            return Ok(false);
+        }
+
+        self.set_control(control, wanted)?;
+        Ok(true)
+    }
+}
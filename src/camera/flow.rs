@@ -0,0 +1,247 @@
+use crate::camera::luma::{LUMA_SCALE, LumaImage};
+
+// Times the luma image is halved before the coarsest search
+const PYRAMID_LEVELS: usize = 2;
+
+// Smallest share of the frame two shifted images have to overlap by for
+// their difference to mean anything
+const MIN_OVERLAP: f32 = 0.5;
+
+// The best shift has to leave at most this share of the average difference
+// over the search, otherwise the view has no texture to match or repeats
+const MAX_COST_RATIO: f32 = 0.7;
+
+// Luma averaged over 2 x 2 blocks
+fn half(image: &LumaImage) -> LumaImage {
+    let (width, height) = (image.dimensions().0 / 2, image.dimensions().1 / 2);
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let sum = u16::from(image.get(2 * x, 2 * y))
+                + u16::from(image.get(2 * x + 1, 2 * y))
+                + u16::from(image.get(2 * x, 2 * y + 1))
+                + u16::from(image.get(2 * x + 1, 2 * y + 1));
+            (sum / 4) as u8
+        })
+        .collect();
+
+    LumaImage::new(pixels, (width, height))
+}
+
+// Average absolute difference between the current image and the previous one
+// moved by `shift`, over where they overlap. None if they barely do
+fn cost(previous: &LumaImage, current: &LumaImage, shift: (isize, isize)) -> Option<f32> {
+    let (width, height) = current.dimensions();
+    let (width, height) = (width as isize, height as isize);
+
+    let columns = shift.0.max(0)..(width + shift.0.min(0));
+    let rows = shift.1.max(0)..(height + shift.1.min(0));
+    let count = columns.len() * rows.len();
+    if (count as f32) < MIN_OVERLAP * (width * height) as f32 {
+        return None;
+    }
+
+    let mut total = 0u64;
+    for y in rows {
+        for x in columns.clone() {
+            let a = current.get(x as usize, y as usize);
+            let b = previous.get((x - shift.0) as usize, (y - shift.1) as usize);
+            total += u64::from(a.abs_diff(b));
+        }
+    }
+
+    Some(total as f32 / count as f32)
+}
+
+// The lowest cost shift within `radius` of `center`, and the average cost
+// over the search
+fn search(
+    previous: &LumaImage,
+    current: &LumaImage,
+    center: (isize, isize),
+    radius: isize,
+) -> Option<((isize, isize), f32, f32)> {
+    let mut best: Option<((isize, isize), f32)> = None;
+    let (mut total, mut count) = (0.0, 0);
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let shift = (center.0 + dx, center.1 + dy);
+            let Some(cost) = cost(previous, current, shift) else {
+                continue;
+            };
+
+            total += cost;
+            count += 1;
+            if best.is_none_or(|b| cost < b.1) {
+                best = Some((shift, cost));
+            }
+        }
+    }
+
+    let (shift, cost) = best?;
+    Some((shift, cost, total / count as f32))
+}
+
+// Offset of the minimum of a parabola through three costs either side of
+// the middle one, from -0.5 to 0.5
+fn subpixel(before: Option<f32>, middle: f32, after: Option<f32>) -> f32 {
+    let (Some(before), Some(after)) = (before, after) else {
+        return 0.0;
+    };
+
+    let curvature = before - 2.0 * middle + after;
+    if curvature <= f32::EPSILON {
+        return 0.0;
+    }
+
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
+// A luma image with its halvings, built once per frame so the next frame can
+// be matched against it without building it again
+pub struct Pyramid {
+    // Full luma resolution first
+    levels: Vec<LumaImage>,
+}
+
+impl Pyramid {
+    pub fn new(luma: &LumaImage) -> Self {
+        let mut levels = vec![luma.clone()];
+        for level in 0..PYRAMID_LEVELS {
+            let halved = half(&levels[level]);
+            levels.push(halved);
+        }
+
+        Self { levels }
+    }
+}
+
+// How far the view moved from the previous image to the current one, in full
+// resolution pixels. Searched coarse to fine, within `radius` pixels of the
+// coarsest level around the predicted shift. None if the images don't match
+// well anywhere
+pub fn estimate_shift(
+    previous: &Pyramid,
+    current: &Pyramid,
+    predicted: (f32, f32),
+    radius: usize,
+) -> Option<(f32, f32)> {
+    if previous.levels[0].dimensions() != current.levels[0].dimensions() {
+        return None;
+    }
+
+    // Full resolution pixels per pixel of the coarsest level
+    let coarsest = (LUMA_SCALE << PYRAMID_LEVELS) as f32;
+    let center = (
+        (predicted.0 / coarsest).round() as isize,
+        (predicted.1 / coarsest).round() as isize,
+    );
+
+    let (mut shift, best, average) = search(
+        &previous.levels[PYRAMID_LEVELS],
+        &current.levels[PYRAMID_LEVELS],
+        center,
+        radius as isize,
+    )?;
+    if average <= f32::EPSILON || best > average * MAX_COST_RATIO {
+        return None;
+    }
+
+    // Each finer level only has to settle the pixel the coarser one rounded
+    for level in (0..PYRAMID_LEVELS).rev() {
+        let center = (shift.0 * 2, shift.1 * 2);
+        (shift, _, _) = search(&previous.levels[level], &current.levels[level], center, 1)?;
+    }
+
+    let (previous, current) = (&previous.levels[0], &current.levels[0]);
+    let at = |dx: isize, dy: isize| cost(previous, current, (shift.0 + dx, shift.1 + dy));
+    let middle = at(0, 0)?;
+    let offset = (
+        subpixel(at(-1, 0), middle, at(1, 0)),
+        subpixel(at(0, -1), middle, at(0, 1)),
+    );
+
+    let scale = LUMA_SCALE as f32;
+    Some((
+        (shift.0 as f32 + offset.0) * scale,
+        (shift.1 as f32 + offset.1) * scale,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Smooth texture at half resolution, seen moved by `shift` full
+    // resolution pixels
+    fn textured(shift: (f32, f32)) -> LumaImage {
+        let (width, height) = (160, 120);
+        let scale = LUMA_SCALE as f32;
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (x, y) = (x as f32 * scale - shift.0, y as f32 * scale - shift.1);
+                let value = 128.0
+                    + 50.0 * (x * 0.07).sin() * (y * 0.05).cos()
+                    + 30.0 * (x * 0.021 + y * 0.033).sin()
+                    + 20.0 * (x * 0.13).cos() * (y * 0.11).sin();
+                value.round().clamp(0.0, 255.0) as u8
+            })
+            .collect();
+
+        LumaImage::new(pixels, (width, height))
+    }
+
+    #[test]
+    fn finds_sub_pixel_shifts() {
+        let previous = Pyramid::new(&textured((0.0, 0.0)));
+        for (shift, predicted) in [
+            ((0.0, 0.0), (0.0, 0.0)),
+            ((3.3, 1.7), (0.0, 0.0)),
+            ((13.0, -5.5), (0.0, 0.0)),
+            ((-40.6, 7.2), (0.0, 0.0)),
+            ((150.4, 3.0), (140.0, 0.0)),
+        ] {
+            let current = Pyramid::new(&textured(shift));
+            let estimate = estimate_shift(&previous, &current, predicted, 8).unwrap();
+            assert!(
+                (estimate.0 - shift.0).abs() < 0.5 && (estimate.1 - shift.1).abs() < 0.5,
+                "moved {:?} but estimated {:?}",
+                shift,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn flat_images_dont_match() {
+        let flat = Pyramid::new(&LumaImage::new(vec![100; 160 * 120], (160, 120)));
+        assert!(estimate_shift(&flat, &flat, (0.0, 0.0), 8).is_none());
+    }
+
+    #[test]
+    fn unrelated_views_dont_match() {
+        // Two different patterns of noise, where no shift is much better
+        // than any other
+        let noise = |seed: u32| {
+            let mut state = seed;
+            let pixels = (0..160 * 120)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 24) as u8
+                })
+                .collect();
+            Pyramid::new(&LumaImage::new(pixels, (160, 120)))
+        };
+
+        assert!(estimate_shift(&noise(1), &noise(2), (0.0, 0.0), 8).is_none());
+    }
+
+    #[test]
+    fn subpixel_finds_the_parabola_minimum() {
+        assert_eq!(subpixel(Some(4.0), 1.0, Some(4.0)), 0.0);
+        assert!((subpixel(Some(3.0), 1.0, Some(5.0)) - -0.1667).abs() < 1e-3);
+        assert_eq!(subpixel(None, 1.0, Some(5.0)), 0.0);
+        assert_eq!(subpixel(Some(1.0), 1.0, Some(1.0)), 0.0);
+    }
+}
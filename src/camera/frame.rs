@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use zune_jpeg::JpegDecoder;
+use zune_jpeg::errors::DecodeErrors;
+use zune_jpeg::zune_core::bytestream::ZCursor;
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+
+use crate::camera::blob::{Blob, BlobTarget, find_blobs};
+use crate::camera::color::{ColorClass, ColorClasses, ColorTable, NO_CLASS, Thresholds};
+use crate::camera::line::{LineDetector, LineEstimate};
+use crate::camera::luma::LumaImage;
+use crate::camera::markers::{Marker, MarkerDetector};
+use crate::camera::morphology::MaskFilter;
+use crate::camera::sampler::{FrameGeometry, FrameSampler};
+
+// Running totals for every pixel of one color, so that all of the statistics
+// below can be read without another pass over the frame
+#[derive(Clone, Copy, Default)]
+pub struct ColorStats {
+    count: usize,
+
+    sum: (u64, u64),
+    sum_squares: (u64, u64),
+    sum_product: u64,
+
+    min: (usize, usize),
+    max: (usize, usize),
+
+    margin_sum: u64,
+    margin_count: usize,
+}
+
+impl ColorStats {
+    // Adds `weight` pixels at (x, y), with their chroma margin if they were
+    // classified directly rather than filled in
+    fn add(&mut self, x: usize, y: usize, weight: usize, margin: Option<u8>) {
+        if self.count == 0 {
+            self.min = (x, y);
+            self.max = (x, y);
+        } else {
+            self.min = (self.min.0.min(x), self.min.1.min(y));
+            self.max = (self.max.0.max(x), self.max.1.max(y));
+        }
+
+        let (x, y, w) = (x as u64, y as u64, weight as u64);
+
+        self.count += weight;
+        self.sum = (self.sum.0 + x * w, self.sum.1 + y * w);
+        self.sum_squares = (
+            self.sum_squares.0 + x * x * w,
+            self.sum_squares.1 + y * y * w,
+        );
+        self.sum_product += x * y * w;
+
+        if let Some(margin) = margin {
+            self.margin_sum += u64::from(margin) * w;
+            self.margin_count += weight;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    // Top left and bottom right corners, both inclusive
+    pub fn bounding_box(&self) -> Option<((usize, usize), (usize, usize))> {
+        if self.count == 0 {
+            return None;
+        }
+
+        Some((self.min, self.max))
+    }
+
+    // Central second moments (mu20, mu02, mu11) normalized by the pixel count,
+    // i.e. the variance of x, the variance of y and their covariance
+    pub fn moments(&self) -> Option<(f32, f32, f32)> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let count = self.count as f64;
+        let mean = (self.sum.0 as f64 / count, self.sum.1 as f64 / count);
+
+        Some((
+            (self.sum_squares.0 as f64 / count - mean.0 * mean.0) as f32,
+            (self.sum_squares.1 as f64 / count - mean.1 * mean.1) as f32,
+            (self.sum_product as f64 / count - mean.0 * mean.1) as f32,
+        ))
+    }
+
+    // How tightly packed the pixels are, 1 for a solid disc or rectangle and
+    // close to 0 for pixels scattered across the frame. Compares the pixel
+    // count with the area of the ellipse two standard deviations out
+    pub fn compactness(&self) -> f32 {
+        let Some((xx, yy, xy)) = self.moments() else {
+            return 0.0;
+        };
+
+        let area = 4.0 * std::f32::consts::PI * (xx * yy - xy * xy).max(0.0).sqrt();
+        if area < 1.0 {
+            // A single pixel or a line, nothing to compare against
+            return 1.0;
+        }
+
+        (self.count as f32 / area).min(1.0)
+    }
+
+    // Average chroma margin of the pixels over the next closest class
+    pub fn mean_margin(&self) -> f32 {
+        if self.margin_count == 0 {
+            return 0.0;
+        }
+
+        self.margin_sum as f32 / self.margin_count as f32
+    }
+}
+
+// Pixel fraction at which a class is as present as it can get
+const FULL_FRACTION: f32 = 0.02;
+
+// Chroma margin at which pixels are unambiguously the class
+const FULL_MARGIN: f32 = 24.0;
+
+// A class that passed its thresholds in a frame
+#[derive(Clone, Copy)]
+pub struct Detection {
+    pub class: u8,
+    // Product of the fraction, compactness and margin scores, from 0 to 1
+    pub confidence: f32,
+    pub fraction: f32,
+    pub compactness: f32,
+    pub margin: f32,
+}
+
+impl Detection {
+    fn score(class: u8, stats: &ColorStats, total: usize) -> Self {
+        let fraction = stats.count() as f32 / total.max(1) as f32;
+        let compactness = stats.compactness();
+        let margin = stats.mean_margin();
+
+        Self {
+            class,
+            confidence: (fraction / FULL_FRACTION).min(1.0)
+                * compactness
+                * (margin / FULL_MARGIN).min(1.0),
+            fraction,
+            compactness,
+            margin,
+        }
+    }
+
+    fn passes(&self, stats: &ColorStats, thresholds: &Thresholds) -> bool {
+        stats.count() >= thresholds.pixels
+            && self.fraction >= thresholds.fraction
+            && self.compactness >= thresholds.compactness
+            && self.margin >= thresholds.margin
+            && self.confidence >= thresholds.confidence
+    }
+}
+
+pub struct Frame {
+    image: Vec<u8>,
+    decode_time: Duration,
+    classify_time: Duration,
+    // Of the upright frame, every coordinate a frame reports is upright
+    dimensions: (usize, usize),
+    raw_dimensions: (usize, usize),
+    geometry: FrameGeometry,
+
+    classes: Arc<ColorClasses>,
+    stats: HashMap<String, ColorStats>,
+    unclassified: ColorStats,
+    // Best first
+    detections: Vec<Detection>,
+    // Connected regions of every class, largest first
+    blobs: Vec<Blob>,
+    // Empty unless markers are being detected
+    markers: Vec<Marker>,
+    // None unless a line is being followed, or when it wasn't found
+    line: Option<LineEstimate>,
+    // Upright and undistorted like the mask, at half resolution
+    luma: LumaImage,
+
+    average: (u8, u8),
+    average_luma: u8,
+}
+
+impl Frame {
+    // USB webcams occasionally hand over truncated or corrupt JPEG data, so
+    // decoding can fail and the frame should just be skipped
+    //
+    // The chroma offset is a white balance correction added to every pixel
+    // before it is classified. The frame statistics still use the raw values
+    //
+    // The image itself is left as it was captured, the mask is built in
+    // upright, undistorted order by looking up where each of its pixels came
+    // from in the sampler's maps. So is the luma image, which markers and
+    // dark lines are found in when there are detectors for them
+    pub fn decode(
+        jpeg: &[u8],
+        color_table: &ColorTable,
+        mask_filter: &MaskFilter,
+        sampler: &mut FrameSampler,
+        marker_detector: Option<&MarkerDetector>,
+        line_detector: Option<&LineDetector>,
+        chroma_offset: (i16, i16),
+    ) -> Result<Self, DecodeErrors> {
+        let mut decoder = JpegDecoder::new(ZCursor::new(jpeg));
+        decoder.set_options(DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::YCbCr));
+
+        let now = SystemTime::now();
+        let image = decoder.decode()?;
+        let decode_time = now.elapsed().unwrap();
+
+        let raw_dimensions = decoder
+            .dimensions()
+            .ok_or(DecodeErrors::FormatStatic("Decoded image has no dimensions"))?;
+        if image.len() != raw_dimensions.0 * raw_dimensions.1 * 3 {
+            return Err(DecodeErrors::FormatStatic("Decoded image has the wrong size"));
+        }
+
+        let geometry = sampler.geometry();
+        let dimensions = geometry.dimensions(raw_dimensions);
+
+        let now = SystemTime::now();
+
+        let classes = color_table.classes().clone();
+
+        // Only the middle pixel of each scale x scale block is classified
+        let scale = mask_filter.scale();
+        let mask_dimensions = (dimensions.0 / scale, dimensions.1 / scale);
+        sampler.prepare(raw_dimensions, scale);
+
+        let mut raw_mask = Vec::with_capacity(mask_dimensions.0 * mask_dimensions.1);
+        let mut margins = Vec::with_capacity(raw_mask.capacity());
+        let mut chroma_total: (usize, usize) = (0, 0);
+        let mut luma_total: usize = 0;
+
+        for &source in sampler.sources() {
+            let index = source * 3;
+            let a = &image[index..index + 3];
+
+            let u = (i16::from(a[1]) + chroma_offset.0).clamp(0, 255) as u8;
+            let v = (i16::from(a[2]) + chroma_offset.1).clamp(0, 255) as u8;
+            let (class, margin) = color_table.classify(a[0], u, v);
+
+            chroma_total = (
+                chroma_total.0 + usize::from(a[1]),
+                chroma_total.1 + usize::from(a[2]),
+            );
+            luma_total += usize::from(a[0]);
+
+            raw_mask.push(class);
+            margins.push(margin);
+        }
+
+        let mask = mask_filter.apply(&raw_mask, mask_dimensions);
+
+        // One entry per class, plus one for unclassified pixels at the end.
+        // Each mask pixel stands for its whole block at full resolution
+        let mut class_stats = vec![ColorStats::default(); classes.len() + 1];
+        for (index, (&class, &raw_class)) in mask.iter().zip(&raw_mask).enumerate() {
+            let slot = if class == NO_CLASS {
+                classes.len()
+            } else {
+                usize::from(class)
+            };
+
+            // Pixels the filters moved into a class have no margin for it
+            let margin = (class == raw_class).then_some(margins[index]);
+
+            let (x, y) = sampler.correct((
+                (index % mask_dimensions.0) * scale + scale / 2,
+                (index / mask_dimensions.0) * scale + scale / 2,
+            ));
+            class_stats[slot].add(x, y, scale * scale, margin);
+        }
+
+        let mut blobs = find_blobs(&mask, mask_dimensions, scale, &classes);
+        for blob in &mut blobs {
+            blob.centroid = sampler.correct(blob.centroid);
+            blob.bounding_box = (
+                sampler.correct(blob.bounding_box.0),
+                sampler.correct(blob.bounding_box.1),
+            );
+        }
+
+        let (luma_sources, luma_dimensions) = sampler.luma_sources();
+        let luma = LumaImage::new(
+            luma_sources.iter().map(|&source| image[source * 3]).collect(),
+            luma_dimensions,
+        );
+
+        let markers = marker_detector.map_or_else(Vec::new, |detector| {
+            detector.detect(&luma, dimensions, |point| sampler.correct_point(point))
+        });
+
+        let line = line_detector.and_then(|detector| {
+            detector.detect(&luma, &mask, mask_dimensions, scale, |point| {
+                sampler.correct_point(point)
+            })
+        });
+
+        let classify_time = now.elapsed().unwrap();
+
+        // Neutral chroma and mid grey for an empty frame
+        let (average, average_luma) = if mask.is_empty() {
+            ((128, 128), 128)
+        } else {
+            (
+                (
+                    (chroma_total.0 / mask.len()) as u8,
+                    (chroma_total.1 / mask.len()) as u8,
+                ),
+                (luma_total / mask.len()) as u8,
+            )
+        };
+
+        let unclassified = class_stats.pop().unwrap();
+        let detections = Self::detect(&classes, &class_stats, dimensions.0 * dimensions.1);
+        let stats = classes
+            .iter()
+            .zip(class_stats)
+            .map(|(class, stats)| (class.name.clone(), stats))
+            .collect();
+
+        Ok(Self {
+            decode_time,
+            classify_time,
+            dimensions,
+            raw_dimensions,
+            geometry,
+
+            classes,
+            stats,
+            unclassified,
+            detections,
+            blobs,
+            markers,
+            line,
+            luma,
+
+            average,
+            average_luma,
+            image,
+        })
+    }
+
+    // Every class that passed its thresholds, ranked by confidence
+    fn detect(classes: &ColorClasses, class_stats: &[ColorStats], total: usize) -> Vec<Detection> {
+        let mut detections: Vec<Detection> = classes
+            .iter()
+            .zip(class_stats)
+            .enumerate()
+            .map(|(id, (class, stats))| (Detection::score(id as u8, stats, total), class, stats))
+            .filter(|(detection, class, stats)| detection.passes(stats, &class.thresholds))
+            .map(|(detection, _, _)| detection)
+            .collect();
+
+        detections.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        detections
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    // Average raw (u, v) chroma over the whole frame
+    pub fn average(&self) -> (u8, u8) {
+        self.average
+    }
+
+    pub fn average_luma(&self) -> u8 {
+        self.average_luma
+    }
+
+    pub fn decode_time(&self) -> Duration {
+        self.decode_time
+    }
+
+    pub fn classify_time(&self) -> Duration {
+        self.classify_time
+    }
+
+    // Raw (y, u, v) of a single pixel
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let (x, y) = self.geometry.raw_pixel((x, y), self.raw_dimensions);
+        let index = (y * self.raw_dimensions.0 + x) * 3;
+        (self.image[index], self.image[index + 1], self.image[index + 2])
+    }
+
+    // Luma of the whole upright frame, row by row
+    pub fn luma(&self) -> Vec<u8> {
+        let (width, height) = self.dimensions;
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.pixel(x, y).0)
+            .collect()
+    }
+
+    // Luma at half resolution, for the detectors that compare frames
+    pub fn luma_image(&self) -> &LumaImage {
+        &self.luma
+    }
+
+    // Statistics for a class by name, None if there is no such class
+    pub fn stats(&self, name: &str) -> Option<&ColorStats> {
+        self.stats.get(name)
+    }
+
+    pub fn class(&self, id: u8) -> Option<&ColorClass> {
+        self.classes.get(id)
+    }
+
+    pub fn detections(&self) -> &[Detection] {
+        &self.detections
+    }
+
+    // The most confident detection's class
+    pub fn closest_class(&self) -> Option<&ColorClass> {
+        self.class(self.detections.first()?.class)
+    }
+
+    // Markers found in the frame, in no particular order
+    pub fn markers(&self) -> &[Marker] {
+        &self.markers
+    }
+
+    // The line being followed, if it was found
+    pub fn line(&self) -> Option<&LineEstimate> {
+        self.line.as_ref()
+    }
+
+    // Blobs of every class, largest first
+    pub fn all_blobs(&self) -> &[Blob] {
+        &self.blobs
+    }
+
+    // Blobs of one class, largest first
+    pub fn blobs(&self, class: u8) -> impl Iterator<Item = &Blob> {
+        self.blobs.iter().filter(move |b| b.class == class)
+    }
+
+    // The blob of a class that actions should go after
+    pub fn target_blob(&self, class: u8, target: BlobTarget) -> Option<&Blob> {
+        target.pick(self.blobs(class))
+    }
+
+    // Confidence of a class whether or not it passed its thresholds
+    pub fn confidence(&self, class: u8) -> f32 {
+        self.class(class)
+            .and_then(|c| self.stats(&c.name))
+            .map_or(0.0, |stats| {
+                Detection::score(class, stats, self.dimensions.0 * self.dimensions.1).confidence
+            })
+    }
+
+    // Equivalent of ColorLocator, but for a single blob so that two objects
+    // of the same color don't average out to the empty space between them.
+    // Falls back to the middle of the frame when nothing matched
+    pub fn color_coordinate(&self, target: BlobTarget) -> (usize, usize) {
+        self.detections
+            .first()
+            .and_then(|d| self.target_blob(d.class, target))
+            .map(|blob| blob.centroid)
+            .unwrap_or((self.dimensions.0 / 2, self.dimensions.1 / 2))
+    }
+
+    fn percentage(&self, stats: &ColorStats) -> f32 {
+        (stats.count() as f32 / (self.dimensions.0 * self.dimensions.1).max(1) as f32) * 100f32
+    }
+
+    pub fn print(&self) {
+        let coordinate = self.color_coordinate(BlobTarget::Largest);
+        let name = self.closest_class().map_or("None", |c| c.name.as_str());
+        let stats = self.stats(name).copied().unwrap_or_default();
+        let ((left, top), (right, bottom)) = stats.bounding_box().unwrap_or_default();
+        let (variance_x, variance_y, _) = stats.moments().unwrap_or_default();
+
+        let mut ranking = String::new();
+        for detection in self.detections() {
+            ranking.push_str(&format!(
+                "{}: confidence {:.2} (fraction {:.4}, compactness {:.2}, margin {:.1})\n",
+                self.class(detection.class).map_or("?", |c| c.name.as_str()),
+                detection.confidence,
+                detection.fraction,
+                detection.compactness,
+                detection.margin
+            ));
+        }
+
+        let mut counts = String::new();
+        for (id, class) in self.classes.iter().enumerate() {
+            let stats = &self.stats[&class.name];
+            counts.push_str(&format!(
+                "{} {} pixels ({:.3}%) in {} blobs, ",
+                stats.count(),
+                class.name,
+                self.percentage(stats),
+                self.blobs(id as u8).count()
+            ));
+        }
+
+        let blob = match self
+            .detections
+            .first()
+            .and_then(|d| self.target_blob(d.class, BlobTarget::Largest))
+        {
+            Some(b) => format!(
+                "The largest blob is a {} with {} pixels, aspect ratio {:.2}, fill ratio {:.2}, \
+                circularity {:.2} and {} corners\n",
+                b.shape(),
+                b.area,
+                b.aspect_ratio(),
+                b.fill_ratio(),
+                b.outline.circularity,
+                b.outline.vertices
+            ),
+            None => String::new(),
+        };
+
+        let mut markers = String::new();
+        for marker in &self.markers {
+            markers.push_str(&format!("{}\n", marker));
+        }
+        if let Some(line) = &self.line {
+            markers.push_str(&format!("{}\n", line));
+        }
+
+        println!(
+            "\x1B[2J\x1B[1;1H\n\
+            This frame has dimensions ({}, {}), decoded in {}ms and classified in {}ms\n\
+            The closest color is {}, with coordinate ({}, {})\n\
+            {}\
+            It spans ({}, {}) to ({}, {}), with a spread of ({:.1}, {:.1})\n\
+            The average chroma is ({}, {}) and the average luma is {}\n\
+            {}and {} uncolored pixels ({:.3}%)\n\
+            {}{}",
+            self.dimensions.0,
+            self.dimensions.1,
+            self.decode_time.as_millis(),
+            self.classify_time.as_millis(),
+            name,
+            coordinate.0,
+            coordinate.1,
+            blob,
+            left,
+            top,
+            right,
+            bottom,
+            variance_x.max(0.0).sqrt(),
+            variance_y.max(0.0).sqrt(),
+            self.average.0,
+            self.average.1,
+            self.average_luma,
+            counts,
+            self.unclassified.count(),
+            self.percentage(&self.unclassified),
+            ranking,
+            markers,
+        );
+    }
+}
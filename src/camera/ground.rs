@@ -0,0 +1,257 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::camera::{
+    BlobTarget, CameraModel, CameraVideoStream, ColorClasses, ColorTable, Frame, FrameGeometry,
+    FrameSampler, LensModel, MaskFilter,
+};
+use crate::camera::linalg::{Matrix, fit_homography, transform};
+use crate::config::{Config, config_path};
+
+const FLOOR_FILE: &str = "floor.conf";
+const FLOOR_CALIBRATION_FILE: &str = "floor_calibration.conf";
+
+// Frames averaged for each marker position
+const CALIBRATION_FRAMES: usize = 10;
+
+// Time to move the marker into place
+const CALIBRATION_DELAY: Duration = Duration::from_secs(5);
+
+// How the floor calibration is run, from floor.conf:
+//
+// marker = red
+// pan = 90
+// tilt = 60
+// points = 30 0, 30 20, 30 -20, 60 0, 60 25, 60 -25
+//
+// The marker is a printed target of one of the color classes, placed flat on
+// the floor at each point in turn. Points are (forward, left) in centimetres
+// from the centre of the robot
+pub struct FloorSettings {
+    pub marker: String,
+    pub pan: u8,
+    pub tilt: u8,
+    pub points: Vec<(f32, f32)>,
+}
+
+impl FloorSettings {
+    pub fn load() -> std::io::Result<Self> {
+        let config = Config::load(&config_path(FLOOR_FILE))?;
+
+        let points_text: String = config.parse_value("", "points")?;
+        let mut points = Vec::new();
+        for point in points_text.split(',') {
+            let mut parts = point.split_whitespace().map(str::parse::<f32>);
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(forward)), Some(Ok(left)), None) => points.push((forward, left)),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid floor point `{}`", point.trim()),
+                    ));
+                }
+            }
+        }
+
+        if points.len() < 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the floor calibration needs at least 4 points",
+            ));
+        }
+
+        Ok(Self {
+            marker: config.parse_value("", "marker")?,
+            pan: config.parse_or("", "pan", 90)?,
+            tilt: config.parse_or("", "tilt", 60)?,
+            points,
+        })
+    }
+}
+
+// Maps frame coordinates to positions on the floor around the robot
+pub struct GroundPlane {
+    // From the upright frame to (forward, left) in centimetres, for the
+    // servo angles below
+    homography: Matrix,
+    pan: u8,
+    tilt: u8,
+}
+
+impl GroundPlane {
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let text: String = config.parse_value("", "homography")?;
+        let values: Vec<f64> = text
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid homography"))?;
+
+        let homography = match values[..] {
+            [a, b, c, d, e, f, g, h, i] => [[a, b, c], [d, e, f], [g, h, i]],
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "the homography needs 9 values",
+                ));
+            }
+        };
+
+        Ok(Self {
+            homography,
+            pan: config.parse_value("", "pan")?,
+            tilt: config.parse_value("", "tilt")?,
+        })
+    }
+
+    // None until the floor has been calibrated
+    pub fn load() -> Option<Self> {
+        Config::load_or_default(FLOOR_CALIBRATION_FILE, "floor calibration", |config| {
+            Self::parse(config).map(Some)
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let values: Vec<String> = self
+            .homography
+            .iter()
+            .flatten()
+            .map(|v| v.to_string())
+            .collect();
+
+        let mut config = Config::new();
+        config.set("", "homography", values.join(", "));
+        config.set("", "pan", self.pan);
+        config.set("", "tilt", self.tilt);
+
+        let path = config_path(FLOOR_CALIBRATION_FILE);
+        config.save(&path)?;
+        println!("Saved the floor calibration to {}", path.display());
+
+        Ok(())
+    }
+
+    // (forward, left) in centimetres of a point on the floor, seen with the
+    // servos at the given angles. The point is first moved to where it would
+    // have been with the camera aimed as it was during calibration. None if
+    // the point is above the horizon
+    pub fn floor_position(
+        &self,
+        point: (f32, f32),
+        dimensions: (usize, usize),
+        model: &CameraModel,
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> Option<(f32, f32)> {
+        let calibrated = (Some(self.pan), Some(self.tilt));
+        let (x, y) = model.reproject(point, dimensions, (pan, tilt), calibrated)?;
+        let (forward, left) = transform(&self.homography, (f64::from(x), f64::from(y)))?;
+
+        // Points past the horizon map behind the robot
+        if forward <= 0.0 {
+            return None;
+        }
+
+        Some((forward as f32, left as f32))
+    }
+}
+
+// Asks for the marker at each configured point and fits the homography to
+// where it was seen. The servos should already be at the settings' angles
+pub fn calibrate_floor(
+    camera_stream: &mut CameraVideoStream,
+    settings: &FloorSettings,
+    model: &CameraModel,
+) -> std::io::Result<()> {
+    let classes = Arc::new(ColorClasses::load());
+    let marker = classes.find(&settings.marker).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("there is no color class called {}", settings.marker),
+        )
+    })?;
+
+    let color_table = ColorTable::new(classes);
+    let mask_filter = MaskFilter::load();
+
+    // Frames have to be sampled the way the pipeline does it, for the fit to
+    // hold for its coordinates
+    let mut sampler = FrameSampler::new(FrameGeometry::new(model.orientation(), LensModel::load()));
+
+    let mut image_points = Vec::new();
+    let mut floor_points = Vec::new();
+    for &(forward, left) in &settings.points {
+        println!(
+            "Place the marker {} cm ahead and {} cm to the left, sampling in {} seconds",
+            forward,
+            left,
+            CALIBRATION_DELAY.as_secs()
+        );
+        sleep(CALIBRATION_DELAY);
+
+        // Throw away whatever was sitting in the driver's buffers
+        for _ in 0..2 {
+            camera_stream.get_next_jpeg()?;
+        }
+
+        let mut sum = (0.0, 0.0);
+        let mut seen = 0;
+        for _ in 0..CALIBRATION_FRAMES {
+            let jpeg = camera_stream.get_next_jpeg()?;
+            let Ok(frame) =
+                Frame::decode(&jpeg, &color_table, &mask_filter, &mut sampler, None, None, (0, 0))
+            else {
+                continue;
+            };
+
+            if let Some(blob) = frame.target_blob(marker, BlobTarget::Largest) {
+                sum = (sum.0 + blob.centroid.0 as f64, sum.1 + blob.centroid.1 as f64);
+                seen += 1;
+            }
+        }
+
+        if seen == 0 {
+            println!("The marker wasn't found, skipping this point");
+            continue;
+        }
+
+        // Through the middle of the centroid pixel
+        let point = (sum.0 / seen as f64 + 0.5, sum.1 / seen as f64 + 0.5);
+        println!("Marker seen at ({:.1}, {:.1})", point.0, point.1);
+
+        image_points.push(point);
+        floor_points.push((f64::from(forward), f64::from(left)));
+    }
+
+    if image_points.len() < 4 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the marker was found at fewer than 4 points",
+        ));
+    }
+
+    let homography = fit_homography(&image_points, &floor_points).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "the marker points don't determine the floor, spread them out more",
+        )
+    })?;
+
+    for (image, floor) in image_points.iter().zip(&floor_points) {
+        if let Some(fitted) = transform(&homography, *image) {
+            println!(
+                "({:.0}, {:.0}) cm was fitted as ({:.1}, {:.1}) cm",
+                floor.0, floor.1, fitted.0, fitted.1
+            );
+        }
+    }
+
+    GroundPlane {
+        homography,
+        pan: settings.pan,
+        tilt: settings.tilt,
+    }
+    .save()
+}
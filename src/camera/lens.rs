@@ -0,0 +1,957 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::camera::linalg::{Matrix, fit_homography, multiply, pose_from_homography, solve};
+use crate::camera::sampler::{FrameGeometry, FrameSampler};
+use crate::camera::{CameraVideoStream, ColorClasses, ColorTable, Frame, MaskFilter};
+use crate::config::{Config, config_path};
+
+const LENS_FILE: &str = "lens.conf";
+const CHECKERBOARD_FILE: &str = "checkerboard.conf";
+
+// Time to move the checkerboard to a new pose between views
+const CALIBRATION_DELAY: Duration = Duration::from_secs(3);
+
+// Fewest views the lens can be solved from
+const MIN_VIEWS: usize = 3;
+
+// Radius of the ring of samples the corner detector compares, in pixels
+const RING_RADIUS: f32 = 5.0;
+
+// Weakest corner response kept, whatever the strongest in the frame is
+const MIN_RESPONSE: f32 = 200.0;
+
+// How far a corner can be from where the grid predicts it, as a fraction of
+// the step between corners
+const GRID_TOLERANCE: f64 = 0.35;
+
+// Levenberg-Marquardt iterations for the final fit
+const FIT_ITERATIONS: usize = 100;
+
+// What is undistorted once a lens is calibrated
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Undistortion {
+    // Every mask pixel is sampled from where the lens put it, so the mask,
+    // the statistics and the blobs are all undistorted
+    Frame,
+    // The mask is classified as captured and only the coordinates it
+    // reports are moved, which keeps blob shapes as the camera saw them
+    Coordinates,
+}
+
+impl FromStr for Undistortion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frame" => Ok(Undistortion::Frame),
+            "coordinates" => Ok(Undistortion::Coordinates),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Undistortion {
+    fn name(&self) -> &'static str {
+        match self {
+            Undistortion::Frame => "frame",
+            Undistortion::Coordinates => "coordinates",
+        }
+    }
+}
+
+// Camera matrix and radial distortion of the lens, for the raw (not yet
+// upright) image
+#[derive(Clone, Copy)]
+pub struct LensModel {
+    // In pixels, for frames of the calibrated dimensions
+    focal: (f64, f64),
+    center: (f64, f64),
+    // Radial coefficients k1 and k2
+    distortion: (f64, f64),
+    dimensions: (usize, usize),
+    undistortion: Undistortion,
+}
+
+impl LensModel {
+    // lens.conf, written by --calibrate-lens:
+    //
+    // width = 1280
+    // height = 720
+    // fx = 912.4
+    // fy = 910.8
+    // cx = 641.2
+    // cy = 355.9
+    // k1 = -0.31
+    // k2 = 0.12
+    // undistort = frame
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let undistortion = match config.get("", "undistort") {
+            None => Undistortion::Frame,
+            Some(u) => u.parse().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("undistort must be frame or coordinates, not {}", u),
+                )
+            })?,
+        };
+
+        let lens = Self {
+            focal: (config.parse_value("", "fx")?, config.parse_value("", "fy")?),
+            center: (config.parse_value("", "cx")?, config.parse_value("", "cy")?),
+            distortion: (config.parse_or("", "k1", 0.0)?, config.parse_or("", "k2", 0.0)?),
+            dimensions: (
+                config.parse_value("", "width")?,
+                config.parse_value("", "height")?,
+            ),
+            undistortion,
+        };
+
+        if lens.focal.0 <= 0.0 || lens.focal.1 <= 0.0 || lens.dimensions.0 * lens.dimensions.1 == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the focal lengths and dimensions must be above 0",
+            ));
+        }
+
+        Ok(lens)
+    }
+
+    // None until the lens has been calibrated
+    pub fn load() -> Option<Self> {
+        Config::load_or_default(LENS_FILE, "lens calibration", |config| {
+            Self::parse(config).map(Some)
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let mut config = Config::new();
+        config.set("", "width", self.dimensions.0);
+        config.set("", "height", self.dimensions.1);
+        config.set("", "fx", self.focal.0);
+        config.set("", "fy", self.focal.1);
+        config.set("", "cx", self.center.0);
+        config.set("", "cy", self.center.1);
+        config.set("", "k1", self.distortion.0);
+        config.set("", "k2", self.distortion.1);
+        config.set("", "undistort", self.undistortion.name());
+
+        let path = config_path(LENS_FILE);
+        config.save(&path)?;
+        println!("Saved the lens calibration to {}", path.display());
+
+        Ok(())
+    }
+
+    pub fn undistortion(&self) -> Undistortion {
+        self.undistortion
+    }
+
+    // Focal lengths and centre for frames of these dimensions, which may be
+    // a different resolution than the calibration was done at
+    fn scaled(&self, dimensions: (usize, usize)) -> ((f64, f64), (f64, f64)) {
+        let scale = (
+            dimensions.0 as f64 / self.dimensions.0 as f64,
+            dimensions.1 as f64 / self.dimensions.1 as f64,
+        );
+
+        (
+            (self.focal.0 * scale.0, self.focal.1 * scale.1),
+            (self.center.0 * scale.0, self.center.1 * scale.1),
+        )
+    }
+
+    fn radial(&self, r2: f64) -> f64 {
+        1.0 + self.distortion.0 * r2 + self.distortion.1 * r2 * r2
+    }
+
+    // Where the lens puts a continuous point of the ideal pinhole image
+    pub fn distort(&self, point: (f64, f64), dimensions: (usize, usize)) -> (f64, f64) {
+        let (focal, center) = self.scaled(dimensions);
+        let x = (point.0 - center.0) / focal.0;
+        let y = (point.1 - center.1) / focal.1;
+        let radial = self.radial(x * x + y * y);
+
+        (center.0 + focal.0 * x * radial, center.1 + focal.1 * y * radial)
+    }
+
+    // The inverse of distort. There's no closed form, so it is found by
+    // fixed point iteration, which converges quickly for webcam lenses
+    pub fn undistort(&self, point: (f64, f64), dimensions: (usize, usize)) -> (f64, f64) {
+        let (focal, center) = self.scaled(dimensions);
+        let distorted = ((point.0 - center.0) / focal.0, (point.1 - center.1) / focal.1);
+
+        let (mut x, mut y) = distorted;
+        for _ in 0..20 {
+            let radial = self.radial(x * x + y * y);
+            if radial <= f64::EPSILON {
+                break;
+            }
+            (x, y) = (distorted.0 / radial, distorted.1 / radial);
+        }
+
+        (center.0 + focal.0 * x, center.1 + focal.1 * y)
+    }
+}
+
+// The printed checkerboard, from checkerboard.conf:
+//
+// columns = 9
+// rows = 6
+// views = 12
+//
+// Columns and rows count the inner corners, where four squares meet, not the
+// squares themselves
+struct CheckerboardSettings {
+    columns: usize,
+    rows: usize,
+    views: usize,
+}
+
+impl Default for CheckerboardSettings {
+    fn default() -> Self {
+        Self {
+            columns: 9,
+            rows: 6,
+            views: 12,
+        }
+    }
+}
+
+impl CheckerboardSettings {
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            columns: config.parse_or("", "columns", defaults.columns)?,
+            rows: config.parse_or("", "rows", defaults.rows)?,
+            views: config.parse_or("", "views", defaults.views)?,
+        };
+
+        if settings.columns < 3 || settings.rows < 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the checkerboard needs at least 3 columns and rows of inner corners",
+            ));
+        }
+
+        if settings.views < MIN_VIEWS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("the lens calibration needs at least {} views", MIN_VIEWS),
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    fn load() -> Self {
+        Config::load_or_default(CHECKERBOARD_FILE, "checkerboard settings", Self::parse)
+    }
+}
+
+// How strongly each pixel looks like a checkerboard corner. Opposite points
+// on a ring around a corner match each other and points a quarter turn away
+// don't, while edges and blobs score badly on one test or the other
+fn corner_response(luma: &[u8], dimensions: (usize, usize)) -> Vec<f32> {
+    let (width, height) = dimensions;
+    let ring: Vec<isize> = (0..16)
+        .map(|n| {
+            let angle = n as f32 * std::f32::consts::PI / 8.0;
+            let x = (RING_RADIUS * angle.cos()).round() as isize;
+            let y = (RING_RADIUS * angle.sin()).round() as isize;
+            y * width as isize + x
+        })
+        .collect();
+
+    let radius = RING_RADIUS.ceil() as usize;
+    let mut response = vec![0.0; width * height];
+    if width <= radius * 2 || height <= radius * 2 {
+        return response;
+    }
+
+    for y in radius..height - radius {
+        for x in radius..width - radius {
+            let index = y * width + x;
+            let samples: Vec<f32> = ring
+                .iter()
+                .map(|offset| f32::from(luma[(index as isize + offset) as usize]))
+                .collect();
+
+            let sum: f32 = (0..4)
+                .map(|n| {
+                    ((samples[n] + samples[n + 8]) - (samples[n + 4] + samples[n + 12])).abs()
+                })
+                .sum();
+            let difference: f32 = (0..8).map(|n| (samples[n] - samples[n + 8]).abs()).sum();
+
+            let ring_mean = samples.iter().sum::<f32>() / 16.0;
+            let local_mean = [index, index - 1, index + 1, index - width, index + width]
+                .iter()
+                .map(|&i| f32::from(luma[i]))
+                .sum::<f32>()
+                / 5.0;
+
+            response[index] = sum - difference - 16.0 * (ring_mean - local_mean).abs();
+        }
+    }
+
+    response
+}
+
+// Sub-pixel positions of the strongest corners, as continuous points
+fn find_corners(luma: &[u8], dimensions: (usize, usize)) -> Vec<(f64, f64)> {
+    let (width, height) = dimensions;
+    let response = corner_response(luma, dimensions);
+
+    let strongest = response.iter().copied().fold(0.0, f32::max);
+    let threshold = (strongest * 0.2).max(MIN_RESPONSE);
+
+    let suppression = 3;
+    let refinement = 2;
+    let mut corners = Vec::new();
+    for y in suppression..height.saturating_sub(suppression) {
+        for x in suppression..width.saturating_sub(suppression) {
+            let value = response[y * width + x];
+            if value < threshold {
+                continue;
+            }
+
+            // Only keep local maxima, ties going to the first pixel
+            let neighbourhood = (y - suppression..=y + suppression)
+                .flat_map(|ny| (x - suppression..=x + suppression).map(move |nx| (nx, ny)));
+            let maximum = neighbourhood.clone().all(|(nx, ny)| {
+                let other = response[ny * width + nx];
+                other < value || (other == value && (ny, nx) >= (y, x))
+            });
+            if !maximum {
+                continue;
+            }
+
+            // Weighted centre of the positive response around the peak
+            let mut total = 0.0;
+            let mut sum = (0.0, 0.0);
+            for (nx, ny) in neighbourhood.filter(|&(nx, ny)| {
+                nx.abs_diff(x) <= refinement && ny.abs_diff(y) <= refinement
+            }) {
+                let weight = f64::from(response[ny * width + nx].max(0.0));
+                total += weight;
+                sum = (sum.0 + weight * nx as f64, sum.1 + weight * ny as f64);
+            }
+
+            // Through the middle of the pixel
+            corners.push((sum.0 / total + 0.5, sum.1 / total + 0.5));
+        }
+    }
+
+    corners
+}
+
+fn sub(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn length(a: (f64, f64)) -> f64 {
+    a.0.hypot(a.1)
+}
+
+// Puts the corners into board order, row by row, or None if the whole board
+// isn't there. The grid is grown outwards from the corner nearest the middle,
+// each step predicted from the last, so it follows the perspective of a
+// tilted board
+fn assemble_grid(corners: &[(f64, f64)], columns: usize, rows: usize) -> Option<Vec<(f64, f64)>> {
+    if corners.len() < columns * rows {
+        return None;
+    }
+
+    let count = corners.len() as f64;
+    let middle = (
+        corners.iter().map(|c| c.0).sum::<f64>() / count,
+        corners.iter().map(|c| c.1).sum::<f64>() / count,
+    );
+    let nearest_to = |point: (f64, f64)| {
+        (0..corners.len()).min_by(|&a, &b| {
+            length(sub(corners[a], point)).total_cmp(&length(sub(corners[b], point)))
+        })
+    };
+
+    // The first step along each axis is to the nearest neighbour, and to the
+    // next nearest that isn't in line with it
+    let seed = nearest_to(middle)?;
+    let mut neighbours: Vec<(f64, f64)> = corners
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != seed)
+        .map(|(_, &c)| sub(c, corners[seed]))
+        .collect();
+    neighbours.sort_by(|a, b| length(*a).total_cmp(&length(*b)));
+
+    let first = *neighbours.first()?;
+    let second = *neighbours.iter().take(8).find(|n| {
+        let cosine = (n.0 * first.0 + n.1 * first.1) / (length(**n) * length(first));
+        cosine.abs() < 0.5
+    })?;
+
+    let mut cells: HashMap<(i32, i32), usize> = HashMap::new();
+    let mut used = vec![false; corners.len()];
+    let mut queue = VecDeque::new();
+
+    cells.insert((0, 0), seed);
+    used[seed] = true;
+    queue.push_back(((0, 0), first, second));
+
+    while let Some((cell, step_i, step_j)) = queue.pop_front() {
+        let position = corners[cells[&cell]];
+        let directions = [
+            ((1, 0), step_i),
+            ((-1, 0), (-step_i.0, -step_i.1)),
+            ((0, 1), step_j),
+            ((0, -1), (-step_j.0, -step_j.1)),
+        ];
+
+        for ((di, dj), step) in directions {
+            let next = (cell.0 + di, cell.1 + dj);
+            if cells.contains_key(&next) {
+                continue;
+            }
+
+            let predicted = (position.0 + step.0, position.1 + step.1);
+            let Some(found) = nearest_to(predicted) else {
+                continue;
+            };
+            if used[found] || length(sub(corners[found], predicted)) > GRID_TOLERANCE * length(step)
+            {
+                continue;
+            }
+
+            cells.insert(next, found);
+            used[found] = true;
+
+            // The step just taken replaces the prediction along its axis
+            let actual = sub(corners[found], position);
+            let sign = f64::from(di + dj);
+            let actual = (actual.0 * sign, actual.1 * sign);
+            if di != 0 {
+                queue.push_back((next, actual, step_j));
+            } else {
+                queue.push_back((next, step_i, actual));
+            }
+        }
+    }
+
+    let min_i = cells.keys().map(|c| c.0).min()?;
+    let max_i = cells.keys().map(|c| c.0).max()?;
+    let min_j = cells.keys().map(|c| c.1).min()?;
+    let max_j = cells.keys().map(|c| c.1).max()?;
+    let size = ((max_i - min_i + 1) as usize, (max_j - min_j + 1) as usize);
+
+    if cells.len() != columns * rows {
+        return None;
+    }
+
+    // The grid may have been grown along the board's rows or its columns
+    let transposed = if size == (columns, rows) {
+        false
+    } else if size == (rows, columns) {
+        true
+    } else {
+        return None;
+    };
+
+    let mut grid = vec![(0.0, 0.0); columns * rows];
+    for (&(i, j), &corner) in &cells {
+        let (i, j) = ((i - min_i) as usize, (j - min_j) as usize);
+        let (column, row) = if transposed { (j, i) } else { (i, j) };
+        grid[row * columns + column] = corners[corner];
+    }
+
+    // Number the corners so that going along a row and then down a column
+    // turns clockwise in the image, as it does on the board seen from the
+    // front. Otherwise the board would look mirrored
+    let along = sub(grid[1], grid[0]);
+    let down = sub(grid[columns], grid[0]);
+    if along.0 * down.1 - along.1 * down.0 < 0.0 {
+        for row in grid.chunks_mut(columns) {
+            row.reverse();
+        }
+    }
+
+    Some(grid)
+}
+
+// Rotation matrix of an axis-angle vector
+fn rotation_matrix(vector: [f64; 3]) -> Matrix {
+    let angle = (vector[0] * vector[0] + vector[1] * vector[1] + vector[2] * vector[2]).sqrt();
+    if angle < 1e-12 {
+        return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+
+    let k = vector.map(|v| v / angle);
+    let (sin, cos) = angle.sin_cos();
+    let skew = [[0.0, -k[2], k[1]], [k[2], 0.0, -k[0]], [-k[1], k[0], 0.0]];
+
+    let mut matrix = [[0.0; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            let identity = if i == j { 1.0 } else { 0.0 };
+            *value = cos * identity + (1.0 - cos) * k[i] * k[j] + sin * skew[i][j];
+        }
+    }
+
+    matrix
+}
+
+// Axis-angle vector of a rotation matrix, the inverse of rotation_matrix
+fn rotation_vector(matrix: &Matrix) -> [f64; 3] {
+    // Twice the sine of the angle times the axis. The angle is taken from
+    // both its sine and cosine, since either alone loses precision near
+    // zero or half a turn
+    let axis = [
+        matrix[2][1] - matrix[1][2],
+        matrix[0][2] - matrix[2][0],
+        matrix[1][0] - matrix[0][1],
+    ];
+    let sin = (axis[0] * axis[0] + axis[1] * axis[1] + axis[2] * axis[2]).sqrt() / 2.0;
+    let cos = (matrix[0][0] + matrix[1][1] + matrix[2][2] - 1.0) / 2.0;
+    let angle = sin.atan2(cos);
+
+    if sin > 1e-6 {
+        return axis.map(|a| a * angle / (2.0 * sin));
+    }
+
+    if cos > 0.0 {
+        return [0.0; 3];
+    }
+
+    // Half a turn, where the axis has to come from the diagonal instead, with
+    // the signs taken relative to its largest component
+    let mut axis = [0, 1, 2].map(|i| ((matrix[i][i] + 1.0) / 2.0).max(0.0).sqrt());
+    let largest = (0..3).max_by(|&a, &b| axis[a].total_cmp(&axis[b])).unwrap();
+    for i in 0..3 {
+        if i != largest && matrix[largest][i] + matrix[i][largest] < 0.0 {
+            axis[i] = -axis[i];
+        }
+    }
+
+    axis.map(|a| a * angle)
+}
+
+// Everything the calibration fits: fx, fy, cx, cy, k1, k2, then for each view
+// the board's rotation vector and translation
+const LENS_PARAMETERS: usize = 6;
+const VIEW_PARAMETERS: usize = 6;
+
+// Where a board point (in squares) shows up in a view
+fn project(parameters: &[f64], view: usize, point: (f64, f64)) -> (f64, f64) {
+    let [fx, fy, cx, cy, k1, k2] = parameters[..LENS_PARAMETERS] else {
+        unreachable!()
+    };
+    let pose = &parameters[LENS_PARAMETERS + view * VIEW_PARAMETERS..][..VIEW_PARAMETERS];
+
+    let rotation = rotation_matrix([pose[0], pose[1], pose[2]]);
+    let camera = [0, 1, 2].map(|i| {
+        rotation[i][0] * point.0 + rotation[i][1] * point.1 + pose[3 + i]
+    });
+
+    // Behind the camera, which only a wild step of the fit can reach
+    if camera[2] <= 1e-9 {
+        return (1e6, 1e6);
+    }
+
+    let (x, y) = (camera[0] / camera[2], camera[1] / camera[2]);
+    let r2 = x * x + y * y;
+    let radial = 1.0 + k1 * r2 + k2 * r2 * r2;
+
+    (cx + fx * x * radial, cy + fy * y * radial)
+}
+
+fn residuals(parameters: &[f64], views: &[Vec<(f64, f64)>], board: &[(f64, f64)]) -> Vec<f64> {
+    let mut residuals = Vec::with_capacity(views.len() * board.len() * 2);
+    for (view, corners) in views.iter().enumerate() {
+        for (&point, corner) in board.iter().zip(corners) {
+            let (x, y) = project(parameters, view, point);
+            residuals.push(x - corner.0);
+            residuals.push(y - corner.1);
+        }
+    }
+
+    residuals
+}
+
+// Focal length of a pinhole camera with its centre at the origin, from the
+// two constraints a board's homography puts on it: the board's axes are at
+// right angles and equally long. None if the view is too face on to tell
+fn focal_from_homography(h: &Matrix) -> Option<f64> {
+    let mut estimates = Vec::new();
+
+    let orthogonal = h[2][0] * h[2][1];
+    if orthogonal.abs() > 1e-12 {
+        estimates.push(-(h[0][0] * h[0][1] + h[1][0] * h[1][1]) / orthogonal);
+    }
+
+    let equal = h[2][0] * h[2][0] - h[2][1] * h[2][1];
+    if equal.abs() > 1e-12 {
+        estimates.push(
+            -(h[0][0] * h[0][0] + h[1][0] * h[1][0] - h[0][1] * h[0][1] - h[1][1] * h[1][1])
+                / equal,
+        );
+    }
+
+    let valid: Vec<f64> = estimates.into_iter().filter(|&f2| f2 > 0.0).collect();
+    if valid.is_empty() {
+        return None;
+    }
+
+    Some((valid.iter().sum::<f64>() / valid.len() as f64).sqrt())
+}
+
+// Board pose in a view from its homography and the camera matrix, as a
+// rotation vector and translation
+fn initial_pose(h: &Matrix, focal: f64, center: (f64, f64)) -> [f64; 6] {
+    let (rotation, [tx, ty, tz]) = pose_from_homography(h, (focal, focal), center);
+    let [rx, ry, rz] = rotation_vector(&rotation);
+
+    [rx, ry, rz, tx, ty, tz]
+}
+
+// Fits the lens to the corners of every view, returning it with the RMS
+// reprojection error in pixels. Starts from a distortion free camera centred
+// on the frame and refines everything together with Levenberg-Marquardt
+fn fit_lens(
+    views: &[Vec<(f64, f64)>],
+    columns: usize,
+    rows: usize,
+    dimensions: (usize, usize),
+) -> Option<(LensModel, f64)> {
+    let board: Vec<(f64, f64)> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column as f64, row as f64)))
+        .collect();
+
+    let center = (dimensions.0 as f64 / 2.0, dimensions.1 as f64 / 2.0);
+    let to_center = [[1.0, 0.0, -center.0], [0.0, 1.0, -center.1], [0.0, 0.0, 1.0]];
+
+    let homographies: Vec<Matrix> = views
+        .iter()
+        .map(|corners| fit_homography(&board, corners))
+        .collect::<Option<_>>()?;
+
+    let mut focals: Vec<f64> = homographies
+        .iter()
+        .filter_map(|h| focal_from_homography(&multiply(&to_center, h)))
+        .collect();
+    focals.sort_by(f64::total_cmp);
+
+    // A 60 degree lens if every view was face on
+    let focal = focals
+        .get(focals.len() / 2)
+        .copied()
+        .unwrap_or(center.0 / 30f64.to_radians().tan());
+
+    let mut parameters = vec![focal, focal, center.0, center.1, 0.0, 0.0];
+    for h in &homographies {
+        parameters.extend(initial_pose(h, focal, center));
+    }
+
+    let mut current = residuals(&parameters, views, &board);
+    let mut cost: f64 = current.iter().map(|r| r * r).sum();
+    let mut damping = 1e-3;
+
+    for _ in 0..FIT_ITERATIONS {
+        // Numerical Jacobian, one column per parameter
+        let jacobian: Vec<Vec<f64>> = (0..parameters.len())
+            .map(|k| {
+                let step = 1e-6 * parameters[k].abs().max(1.0);
+                let mut moved = parameters.clone();
+                moved[k] += step;
+                residuals(&moved, views, &board)
+                    .iter()
+                    .zip(&current)
+                    .map(|(a, b)| (a - b) / step)
+                    .collect()
+            })
+            .collect();
+
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+        let normal: Vec<Vec<f64>> = jacobian
+            .iter()
+            .map(|a| jacobian.iter().map(|b| dot(a, b)).collect())
+            .collect();
+        let gradient: Vec<f64> = jacobian.iter().map(|a| -dot(a, &current)).collect();
+
+        let mut improved = false;
+        while damping < 1e10 {
+            let mut damped = normal.clone();
+            for (k, row) in damped.iter_mut().enumerate() {
+                row[k] += damping * (normal[k][k] + 1e-9);
+            }
+
+            if let Some(delta) = solve(damped, gradient.clone()) {
+                let candidate: Vec<f64> =
+                    parameters.iter().zip(&delta).map(|(p, d)| p + d).collect();
+                let candidate_residuals = residuals(&candidate, views, &board);
+                let candidate_cost: f64 = candidate_residuals.iter().map(|r| r * r).sum();
+
+                if candidate_cost < cost {
+                    let converged = cost - candidate_cost < cost * 1e-10;
+                    parameters = candidate;
+                    current = candidate_residuals;
+                    cost = candidate_cost;
+                    damping = (damping / 10.0).max(1e-12);
+                    improved = !converged;
+                    break;
+                }
+            }
+
+            damping *= 10.0;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    if parameters[0] <= 0.0 || parameters[1] <= 0.0 {
+        return None;
+    }
+
+    let lens = LensModel {
+        focal: (parameters[0], parameters[1]),
+        center: (parameters[2], parameters[3]),
+        distortion: (parameters[4], parameters[5]),
+        dimensions,
+        undistortion: LensModel::load().map_or(Undistortion::Frame, |l| l.undistortion),
+    };
+
+    Some((lens, (cost / (views.len() * board.len()) as f64).sqrt()))
+}
+
+// Captures the checkerboard in several poses and fits the camera matrix and
+// distortion to them. Frames are taken as the camera sends them, neither
+// turned upright nor undistorted, which is what the lens model describes
+pub fn calibrate_lens(camera_stream: &mut CameraVideoStream) -> std::io::Result<()> {
+    let settings = CheckerboardSettings::load();
+
+    // Only the raw pixels of the frames are used
+    let color_table = ColorTable::new(Arc::new(ColorClasses::load()));
+    let mask_filter = MaskFilter::default();
+    let mut sampler = FrameSampler::new(FrameGeometry::default());
+
+    let mut views = Vec::new();
+    let mut dimensions = None;
+    let mut attempts = 0;
+    while views.len() < settings.views && attempts < settings.views * 3 {
+        attempts += 1;
+        println!(
+            "Hold the checkerboard at a new angle, capturing view {} of {} in {} seconds",
+            views.len() + 1,
+            settings.views,
+            CALIBRATION_DELAY.as_secs()
+        );
+        sleep(CALIBRATION_DELAY);
+
+        // Throw away whatever was sitting in the driver's buffers
+        for _ in 0..2 {
+            camera_stream.get_next_jpeg()?;
+        }
+
+        let jpeg = camera_stream.get_next_jpeg()?;
+        let Ok(frame) =
+            Frame::decode(&jpeg, &color_table, &mask_filter, &mut sampler, None, None, (0, 0))
+        else {
+            println!("The frame was corrupt, try again");
+            continue;
+        };
+
+        if dimensions.is_some_and(|d| d != frame.dimensions()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the frame size changed during the calibration",
+            ));
+        }
+        dimensions = Some(frame.dimensions());
+
+        let corners = find_corners(&frame.luma(), frame.dimensions());
+        match assemble_grid(&corners, settings.columns, settings.rows) {
+            Some(grid) => {
+                println!("Found the checkerboard");
+                views.push(grid);
+            }
+            None => println!(
+                "The checkerboard wasn't found ({} corner candidates), keep all of it in view",
+                corners.len()
+            ),
+        }
+    }
+
+    let Some(dimensions) = dimensions.filter(|_| views.len() >= MIN_VIEWS) else {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("the checkerboard was found in fewer than {} views", MIN_VIEWS),
+        ));
+    };
+
+    let (lens, error) = fit_lens(&views, settings.columns, settings.rows, dimensions)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "the views don't determine the lens, vary the board's angle more",
+            )
+        })?;
+
+    println!("Camera matrix:");
+    println!("  {:8.1} {:8.1} {:8.1}", lens.focal.0, 0.0, lens.center.0);
+    println!("  {:8.1} {:8.1} {:8.1}", 0.0, lens.focal.1, lens.center.1);
+    println!("  {:8.1} {:8.1} {:8.1}", 0.0, 0.0, 1.0);
+    println!(
+        "Distortion: k1 = {:.4}, k2 = {:.4}",
+        lens.distortion.0, lens.distortion.1
+    );
+    println!("Reprojection error: {:.2} pixels over {} views", error, views.len());
+
+    lens.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMENSIONS: (usize, usize) = (320, 240);
+    const COLUMNS: usize = 7;
+    const ROWS: usize = 5;
+
+    fn lens() -> LensModel {
+        LensModel {
+            focal: (280.0, 276.0),
+            center: (163.0, 118.0),
+            distortion: (-0.2, 0.05),
+            dimensions: DIMENSIONS,
+            undistortion: Undistortion::Frame,
+        }
+    }
+
+    // Rotation and translation putting the middle of the board `distance`
+    // squares in front of the camera
+    fn pose(rotation: [f64; 3], distance: f64) -> (Matrix, [f64; 3]) {
+        let rotation = rotation_matrix(rotation);
+        let middle = ((COLUMNS - 1) as f64 / 2.0, (ROWS - 1) as f64 / 2.0);
+        let translation = [0, 1, 2].map(|i| {
+            let offset = if i == 2 { distance } else { 0.0 };
+            offset - rotation[i][0] * middle.0 - rotation[i][1] * middle.1
+        });
+
+        (rotation, translation)
+    }
+
+    // The checkerboard as the lens sees it, each pixel averaged over 4 x 4
+    // samples. The board is white beyond its outer squares
+    fn render(lens: &LensModel, (rotation, translation): (Matrix, [f64; 3])) -> Vec<u8> {
+        let normal = [rotation[0][2], rotation[1][2], rotation[2][2]];
+        let along_normal = normal.iter().zip(&translation).map(|(n, t)| n * t).sum::<f64>();
+
+        let (width, height) = DIMENSIONS;
+        let mut luma = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut white = 0;
+                for sample in 0..16 {
+                    let point = (
+                        x as f64 + (sample % 4) as f64 / 4.0 + 0.125,
+                        y as f64 + (sample / 4) as f64 / 4.0 + 0.125,
+                    );
+                    let ideal = lens.undistort(point, DIMENSIONS);
+                    let ray = [
+                        (ideal.0 - lens.center.0) / lens.focal.0,
+                        (ideal.1 - lens.center.1) / lens.focal.1,
+                        1.0,
+                    ];
+
+                    // Where the ray meets the board, in squares
+                    let scale = along_normal / (0..3).map(|i| normal[i] * ray[i]).sum::<f64>();
+                    let camera = [0, 1, 2].map(|i| ray[i] * scale - translation[i]);
+                    let u = (0..3).map(|i| rotation[i][0] * camera[i]).sum::<f64>();
+                    let v = (0..3).map(|i| rotation[i][1] * camera[i]).sum::<f64>();
+
+                    let on_board = u > -1.0 && v > -1.0 && u < COLUMNS as f64 && v < ROWS as f64;
+                    if !on_board || (u.floor() + v.floor()) as i64 % 2 == 0 {
+                        white += 1;
+                    }
+                }
+
+                luma.push((30 + white * 200 / 16) as u8);
+            }
+        }
+
+        luma
+    }
+
+    #[test]
+    fn rotation_vectors_round_trip() {
+        let vectors = [
+            [0.0, 0.0, 0.0],
+            [0.3, -0.2, 0.1],
+            [-1.2, 0.4, 2.0],
+            [0.0, 0.0, 3.0],
+            [std::f64::consts::PI, 0.0, 0.0],
+            [0.0, -std::f64::consts::PI / 2.0_f64.sqrt(), std::f64::consts::PI / 2.0_f64.sqrt()],
+        ];
+
+        for vector in vectors {
+            let back = rotation_vector(&rotation_matrix(vector));
+            let matrix = rotation_matrix(back);
+            let expected = rotation_matrix(vector);
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert!((matrix[i][j] - expected[i][j]).abs() < 1e-9, "{:?}", vector);
+                }
+            }
+
+            // Below half a turn the vector itself comes back
+            let angle = vector.iter().map(|v| v * v).sum::<f64>().sqrt();
+            if angle < 3.0 {
+                for i in 0..3 {
+                    assert!((back[i] - vector[i]).abs() < 1e-9, "{:?} {:?}", vector, back);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rendered_checkerboards_recover_the_lens() {
+        let lens = lens();
+        let rotations = [
+            [0.35, 0.0, 0.05],
+            [-0.3, 0.15, 0.0],
+            [0.05, 0.4, -0.1],
+            [0.2, -0.35, 0.1],
+            [-0.15, -0.2, 0.3],
+        ];
+
+        let views: Vec<Vec<(f64, f64)>> = rotations
+            .iter()
+            .map(|&rotation| {
+                let luma = render(&lens, pose(rotation, 9.0));
+                let corners = find_corners(&luma, DIMENSIONS);
+                assemble_grid(&corners, COLUMNS, ROWS).expect("the board wasn't found")
+            })
+            .collect();
+
+        let (fitted, error) = fit_lens(&views, COLUMNS, ROWS, DIMENSIONS).unwrap();
+        assert!(error < 0.5, "reprojection error {:.3}", error);
+        assert!((fitted.focal.0 - lens.focal.0).abs() < 0.03 * lens.focal.0);
+        assert!((fitted.focal.1 - lens.focal.1).abs() < 0.03 * lens.focal.1);
+        assert!((fitted.center.0 - lens.center.0).abs() < 4.0);
+        assert!((fitted.center.1 - lens.center.1).abs() < 4.0);
+        assert!((fitted.distortion.0 - lens.distortion.0).abs() < 0.05);
+
+        // k1 and k2 trade off against each other, but together they have to
+        // bend the image the same way out to where the board was seen
+        for point in [(40.0, 30.0), (280.0, 40.0), (60.0, 200.0), (160.0, 120.0)] {
+            let (a, b) = (fitted.distort(point, DIMENSIONS), lens.distort(point, DIMENSIONS));
+            assert!(length(sub(a, b)) < 2.0, "{:?} went to {:?} not {:?}", point, a, b);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+pub type Matrix = [[f64; 3]; 3];
+
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [[0.0; 3]; 3];
+    for (row, product_row) in product.iter_mut().enumerate() {
+        for (column, value) in product_row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[row][k] * b[k][column]).sum();
+        }
+    }
+
+    product
+}
+
+// Applies a homography to a point, None if it maps to infinity
+pub fn transform(matrix: &Matrix, point: (f64, f64)) -> Option<(f64, f64)> {
+    let [x, y, w] = matrix.map(|row| row[0] * point.0 + row[1] * point.1 + row[2]);
+    if w.abs() < f64::EPSILON {
+        return None;
+    }
+
+    Some((x / w, y / w))
+}
+
+// Moves the points' centre to the origin and scales them to an average
+// distance of sqrt(2) from it, which keeps the homography fit well
+// conditioned. Returns the transform and its inverse
+fn normalization(points: &[(f64, f64)]) -> (Matrix, Matrix) {
+    let count = points.len() as f64;
+    let mean = (
+        points.iter().map(|p| p.0).sum::<f64>() / count,
+        points.iter().map(|p| p.1).sum::<f64>() / count,
+    );
+    let spread = points
+        .iter()
+        .map(|p| (p.0 - mean.0).hypot(p.1 - mean.1))
+        .sum::<f64>()
+        / count;
+    let scale = if spread > f64::EPSILON {
+        std::f64::consts::SQRT_2 / spread
+    } else {
+        1.0
+    };
+
+    (
+        [
+            [scale, 0.0, -scale * mean.0],
+            [0.0, scale, -scale * mean.1],
+            [0.0, 0.0, 1.0],
+        ],
+        [
+            [1.0 / scale, 0.0, mean.0],
+            [0.0, 1.0 / scale, mean.1],
+            [0.0, 0.0, 1.0],
+        ],
+    )
+}
+
+// Gaussian elimination with partial pivoting on a square system, None if it
+// is singular
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let size = b.len();
+    for column in 0..size {
+        let pivot = (column..size)
+            .max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+
+        let pivot_row = a[column].clone();
+        for row in column + 1..size {
+            let factor = a[row][column] / pivot_row[column];
+            for (value, pivot) in a[row][column..].iter_mut().zip(&pivot_row[column..]) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+
+    let mut solution = vec![0.0; size];
+    for row in (0..size).rev() {
+        let known: f64 = (row + 1..size).map(|k| a[row][k] * solution[k]).sum();
+        solution[row] = (b[row] - known) / a[row][row];
+    }
+
+    Some(solution)
+}
+
+// Least squares homography taking each `from` point to its `to` point, from
+// at least 4 pairs
+pub fn fit_homography(from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<Matrix> {
+    let (from_normalize, _) = normalization(from);
+    let (to_normalize, to_denormalize) = normalization(to);
+
+    // Each pair gives two rows of A h = b, with the last entry of the
+    // homography fixed at 1. Solved through the normal equations
+    let mut ata = vec![vec![0.0; 8]; 8];
+    let mut atb = vec![0.0; 8];
+    for (&f, &t) in from.iter().zip(to) {
+        let (u, v) = transform(&from_normalize, f)?;
+        let (x, y) = transform(&to_normalize, t)?;
+
+        let rows = [
+            ([u, v, 1.0, 0.0, 0.0, 0.0, -x * u, -x * v], x),
+            ([0.0, 0.0, 0.0, u, v, 1.0, -y * u, -y * v], y),
+        ];
+        for (row, value) in rows {
+            for (i, ata_row) in ata.iter_mut().enumerate() {
+                for (j, entry) in ata_row.iter_mut().enumerate() {
+                    *entry += row[i] * row[j];
+                }
+                atb[i] += row[i] * value;
+            }
+        }
+    }
+
+    let h = solve(ata, atb)?;
+    let normalized = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]];
+
+    Some(multiply(&to_denormalize, &multiply(&normalized, &from_normalize)))
+}
+
+pub fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    a.map(|v| v / length)
+}
+
+// Pose of a plane from its homography into the image and the camera's focal
+// lengths and centre in pixels, as the rotation and translation taking plane
+// points (x, y, 0) into the camera's frame (right, down, forward). The
+// translation is in the plane's units
+pub fn pose_from_homography(
+    h: &Matrix,
+    focal: (f64, f64),
+    center: (f64, f64),
+) -> (Matrix, [f64; 3]) {
+    let inverse = [
+        [1.0 / focal.0, 0.0, -center.0 / focal.0],
+        [0.0, 1.0 / focal.1, -center.1 / focal.1],
+        [0.0, 0.0, 1.0],
+    ];
+    let m = multiply(&inverse, h);
+    let column = |c: usize| [m[0][c], m[1][c], m[2][c]];
+
+    // Pick the scale that puts the plane in front of the camera
+    let first = column(0);
+    let mut scale = 1.0 / (first[0] * first[0] + first[1] * first[1] + first[2] * first[2]).sqrt();
+    if m[2][2] * scale < 0.0 {
+        scale = -scale;
+    }
+
+    // Noise leaves the axes not quite at right angles, so straighten them
+    let r1 = normalize(first.map(|v| v * scale));
+    let r2 = column(1).map(|v| v * scale);
+    let along = r1[0] * r2[0] + r1[1] * r2[1] + r1[2] * r2[2];
+    let r2 = normalize([0, 1, 2].map(|i| r2[i] - along * r1[i]));
+    let r3 = cross(r1, r2);
+
+    let rotation = [0, 1, 2].map(|i| [r1[i], r2[i], r3[i]]);
+    (rotation, column(2).map(|v| v * scale))
+}
@@ -0,0 +1,406 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::camera::ColorClasses;
+use crate::camera::color::NO_CLASS;
+use crate::camera::linalg::solve;
+use crate::camera::luma::{LUMA_SCALE, LumaImage};
+use crate::config::Config;
+
+const LINE_FILE: &str = "line.conf";
+
+// Bands the line has to be found in to estimate its angle
+const MIN_POINTS: usize = 2;
+
+// Fraction of a mask column's pixels in a band that have to be the class for
+// the column to be part of the line
+const MIN_CLASS_FRACTION: f32 = 0.5;
+
+// What the line looks like against the floor
+#[derive(Clone, PartialEq, Eq)]
+pub enum LineColor {
+    // Darker than the floor around it, e.g. black tape on a light floor
+    Dark,
+    // Pixels of a color class, by name
+    Class(String),
+}
+
+impl FromStr for LineColor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(()),
+            "dark" => Ok(LineColor::Dark),
+            _ => Ok(LineColor::Class(s.to_string())),
+        }
+    }
+}
+
+// line.conf:
+//
+// color = dark
+// region = 0.5
+// bands = 6
+// contrast = 40
+// min_width = 0.02
+// max_width = 0.3
+// max_jump = 0.15
+// speed = 60
+// min_speed = 30
+// turn_angle = 45
+// strafe_gain = 1
+// curvature_slowdown = 2
+#[derive(Clone)]
+pub struct LineSettings {
+    color: LineColor,
+    // Lower part of the frame the line is looked for in, as a fraction of
+    // its height
+    region: f32,
+    bands: usize,
+    // How much darker than the middle of a band a dark line is, in luma
+    // levels
+    contrast: f32,
+    // Narrowest and widest the line can look, and furthest it can move
+    // sideways from one band to the next, as fractions of the frame width
+    min_width: f32,
+    max_width: f32,
+    max_jump: f32,
+    // Motor speeds on a straight line and on the tightest curve
+    pub speed: u8,
+    pub min_speed: u8,
+    // Line angle in degrees at which the robot turns as hard as it drives
+    // forward
+    pub turn_angle: f32,
+    // How hard the robot moves sideways towards a line that is off centre,
+    // relative to driving forward
+    pub strafe_gain: f32,
+    // How quickly the speed drops as the line bends, per unit of curvature
+    pub curvature_slowdown: f32,
+}
+
+impl Default for LineSettings {
+    fn default() -> Self {
+        Self {
+            color: LineColor::Dark,
+            region: 0.5,
+            bands: 6,
+            contrast: 40.0,
+            min_width: 0.02,
+            max_width: 0.3,
+            max_jump: 0.15,
+            speed: 60,
+            min_speed: 30,
+            turn_angle: 45.0,
+            strafe_gain: 1.0,
+            curvature_slowdown: 2.0,
+        }
+    }
+}
+
+impl LineSettings {
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+
+        let color = match config.get("", "color") {
+            None => defaults.color.clone(),
+            Some(c) => c.parse().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "color must be dark or the name of a color class",
+                )
+            })?,
+        };
+
+        let settings = Self {
+            color,
+            region: config.parse_or("", "region", defaults.region)?,
+            bands: config.parse_or("", "bands", defaults.bands)?,
+            contrast: config.parse_or("", "contrast", defaults.contrast)?,
+            min_width: config.parse_or("", "min_width", defaults.min_width)?,
+            max_width: config.parse_or("", "max_width", defaults.max_width)?,
+            max_jump: config.parse_or("", "max_jump", defaults.max_jump)?,
+            speed: config.parse_or("", "speed", defaults.speed)?,
+            min_speed: config.parse_or("", "min_speed", defaults.min_speed)?,
+            turn_angle: config.parse_or("", "turn_angle", defaults.turn_angle)?,
+            strafe_gain: config.parse_or("", "strafe_gain", defaults.strafe_gain)?,
+            curvature_slowdown: config.parse_or(
+                "",
+                "curvature_slowdown",
+                defaults.curvature_slowdown,
+            )?,
+        };
+
+        if settings.region <= 0.0 || settings.region > 1.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the region must be above 0 and at most 1",
+            ));
+        }
+
+        if settings.bands < MIN_POINTS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("there must be at least {} bands", MIN_POINTS),
+            ));
+        }
+
+        if settings.min_width > settings.max_width || settings.turn_angle <= 0.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "min_width must be at most max_width and the turn angle above 0",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(LINE_FILE, "line settings", Self::parse)
+    }
+}
+
+// Where the line runs through the bottom of the frame
+#[derive(Clone)]
+pub struct LineEstimate {
+    // Of the line where it leaves the bottom of the frame, from -1 at the
+    // left edge to 1 at the right edge
+    pub offset: f32,
+    // In degrees from straight up the frame, positive when the line leans
+    // right further up
+    pub angle: f32,
+    // One over the radius the line bends with, in frame heights, positive
+    // when it bends right further up
+    pub curvature: f32,
+    // The middle of the line in each band it was found in, bottom first
+    pub points: Vec<(f32, f32)>,
+}
+
+impl fmt::Display for LineEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The line is {:.2} off centre at {:.0}° with a curvature of {:.2}, seen in {} bands",
+            self.offset,
+            self.angle,
+            self.curvature,
+            self.points.len()
+        )
+    }
+}
+
+pub struct LineDetector {
+    settings: LineSettings,
+    // Of a colored line, NO_CLASS for a dark one
+    class: u8,
+}
+
+impl LineDetector {
+    // Looks for a dark line instead when the line's color class isn't
+    // configured, rather than never finding the line. The rest of the
+    // settings are kept
+    pub fn new(mut settings: LineSettings, classes: &ColorClasses) -> Self {
+        let class = match &settings.color {
+            LineColor::Dark => NO_CLASS,
+            LineColor::Class(name) => match classes.find(name) {
+                Some(class) => class,
+                None => {
+                    println!("{} isn't a color class, following a dark line instead", name);
+                    settings.color = LineColor::Dark;
+                    NO_CLASS
+                }
+            },
+        };
+
+        Self { settings, class }
+    }
+
+    pub fn settings(&self) -> &LineSettings {
+        &self.settings
+    }
+
+    // Whether each column of a band is part of the line, from the luma image.
+    // Columns are compared with the band's median so the floor's brightness
+    // doesn't matter
+    fn dark_columns(&self, luma: &LumaImage, rows: (usize, usize)) -> Vec<bool> {
+        let width = luma.dimensions().0;
+        let count = (rows.1 - rows.0).max(1) as f32;
+
+        let profile: Vec<f32> = (0..width)
+            .map(|x| (rows.0..rows.1).map(|y| f32::from(luma.get(x, y))).sum::<f32>() / count)
+            .collect();
+
+        let mut sorted = profile.clone();
+        sorted.sort_by(f32::total_cmp);
+        let Some(&median) = sorted.get(sorted.len() / 2) else {
+            return Vec::new();
+        };
+
+        profile.iter().map(|&l| l < median - self.settings.contrast).collect()
+    }
+
+    // The same from the class mask
+    fn class_columns(
+        mask: &[u8],
+        mask_dimensions: (usize, usize),
+        rows: (usize, usize),
+        class: u8,
+    ) -> Vec<bool> {
+        let count = (rows.1 - rows.0).max(1) as f32;
+
+        (0..mask_dimensions.0)
+            .map(|x| {
+                let matching = (rows.0..rows.1)
+                    .filter(|y| mask[y * mask_dimensions.0 + x] == class)
+                    .count();
+                matching as f32 / count >= MIN_CLASS_FRACTION
+            })
+            .collect()
+    }
+
+    // Every run of line columns, as the continuous x of its middle and its
+    // width, in columns
+    fn runs(columns: &[bool]) -> Vec<(f32, usize)> {
+        let mut runs = Vec::new();
+        let mut start = None;
+        for (x, &line) in columns.iter().chain([&false]).enumerate() {
+            match (line, start) {
+                (true, None) => start = Some(x),
+                (false, Some(s)) => {
+                    runs.push(((s + x) as f32 / 2.0, x - s));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+
+        runs
+    }
+
+    // Finds the line in each band from the bottom of the frame up, following
+    // it from one band to the next. The luma image is for dark lines and the
+    // mask, with its scale, for colored ones
+    pub fn detect(
+        &self,
+        luma: &LumaImage,
+        mask: &[u8],
+        mask_dimensions: (usize, usize),
+        scale: usize,
+        correct: impl Fn((f32, f32)) -> (f32, f32),
+    ) -> Option<LineEstimate> {
+        // Frame pixels per column, and the rows the line is looked for in
+        let (cell, rows) = match &self.settings.color {
+            LineColor::Dark => (LUMA_SCALE, luma.dimensions().1),
+            LineColor::Class(_) => (scale, mask_dimensions.1),
+        };
+
+        let dimensions = (luma.dimensions().0 * LUMA_SCALE, luma.dimensions().1 * LUMA_SCALE);
+        let width = dimensions.0 as f32;
+        let (min_width, max_width) = (
+            self.settings.min_width * width,
+            self.settings.max_width * width,
+        );
+
+        let region = ((rows as f32 * self.settings.region) as usize).min(rows);
+        let band_rows = region / self.settings.bands;
+        if band_rows == 0 {
+            return None;
+        }
+
+        let mut points = Vec::new();
+        let mut last_x = width / 2.0;
+        for band in 0..self.settings.bands {
+            let bottom = rows - band * band_rows;
+            let band = (bottom - band_rows, bottom);
+
+            let columns = match &self.settings.color {
+                LineColor::Dark => self.dark_columns(luma, band),
+                LineColor::Class(_) => {
+                    Self::class_columns(mask, mask_dimensions, band, self.class)
+                }
+            };
+
+            // Closest to where the line was in the band below, or to the
+            // middle of the frame for the first one
+            let nearest = Self::runs(&columns)
+                .into_iter()
+                .map(|(x, run)| (x * cell as f32, (run * cell) as f32))
+                .filter(|&(_, run)| run >= min_width && run <= max_width)
+                .map(|(x, _)| x)
+                .min_by(|a, b| (a - last_x).abs().total_cmp(&(b - last_x).abs()));
+
+            let Some(x) = nearest else {
+                // Lost above here, but whatever was found below still counts
+                if points.is_empty() {
+                    continue;
+                }
+                break;
+            };
+
+            if !points.is_empty() && (x - last_x).abs() > self.settings.max_jump * width {
+                break;
+            }
+
+            last_x = x;
+            let y = (band.0 + band.1) as f32 / 2.0 * cell as f32;
+            points.push(correct((x, y)));
+        }
+
+        fit_line(points, dimensions)
+    }
+}
+
+// Fits x as a polynomial of the height up the frame, a straight line through
+// two points and a parabola through more
+fn fit_line(points: Vec<(f32, f32)>, dimensions: (usize, usize)) -> Option<LineEstimate> {
+    if points.len() < MIN_POINTS {
+        return None;
+    }
+
+    let (width, height) = (dimensions.0 as f64, dimensions.1 as f64);
+    let half_width = width / 2.0;
+
+    // Heights from 0 at the bottom to 1 at the top, and x from -1 to 1
+    let samples: Vec<(f64, f64)> = points
+        .iter()
+        .map(|&(x, y)| {
+            (
+                (height - f64::from(y)) / height,
+                (f64::from(x) - half_width) / half_width,
+            )
+        })
+        .collect();
+
+    // Least squares through the normal equations
+    let terms = points.len().min(3);
+    let mut a = vec![vec![0.0; terms]; terms];
+    let mut b = vec![0.0; terms];
+    for &(t, u) in &samples {
+        let powers: Vec<f64> = (0..terms).map(|p| t.powi(p as i32)).collect();
+        for row in 0..terms {
+            for column in 0..terms {
+                a[row][column] += powers[row] * powers[column];
+            }
+            b[row] += powers[row] * u;
+        }
+    }
+    let coefficients = solve(a, b)?;
+
+    let offset = coefficients[0];
+    let slope = coefficients[1];
+    let bend = coefficients.get(2).copied().unwrap_or(0.0);
+
+    // Back to pixels: the first and second derivative of x with height up
+    // the frame at its bottom
+    let dx = slope * half_width / height;
+    let ddx = 2.0 * bend * half_width / (height * height);
+    let curvature = ddx / (1.0 + dx * dx).powf(1.5) * height;
+
+    Some(LineEstimate {
+        offset: offset.clamp(-1.0, 1.0) as f32,
+        angle: dx.atan().to_degrees() as f32,
+        curvature: curvature as f32,
+        points,
+    })
+}
@@ -0,0 +1,58 @@
+// Frame pixels per luma image pixel along each side
+pub const LUMA_SCALE: usize = 2;
+
+// Brightness of the upright frame at a reduced resolution, for the detectors
+// that look at shapes and texture rather than color
+#[derive(Clone)]
+pub struct LumaImage {
+    pixels: Vec<u8>,
+    dimensions: (usize, usize),
+}
+
+impl LumaImage {
+    pub fn new(pixels: Vec<u8>, dimensions: (usize, usize)) -> Self {
+        Self { pixels, dimensions }
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.dimensions.0 + x]
+    }
+
+    // Bilinear interpolation at a continuous point, where pixel (0, 0)
+    // covers (0, 0) to (1, 1). None outside the image
+    pub fn sample(&self, point: (f32, f32)) -> Option<f32> {
+        let (x, y) = (point.0 - 0.5, point.1 - 0.5);
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+
+        let (left, top) = (x as usize, y as usize);
+        if left + 1 >= self.dimensions.0 || top + 1 >= self.dimensions.1 {
+            return None;
+        }
+
+        let (dx, dy) = (x - left as f32, y - top as f32);
+        let pixel = |x: usize, y: usize| f32::from(self.get(x, y));
+
+        Some(
+            (pixel(left, top) * (1.0 - dx) + pixel(left + 1, top) * dx) * (1.0 - dy)
+                + (pixel(left, top + 1) * (1.0 - dx) + pixel(left + 1, top + 1) * dx) * dy,
+        )
+    }
+
+    // A continuous point of this image in full resolution frame coordinates.
+    // Each pixel was sampled from the middle of its block
+    pub fn to_frame(point: (f32, f32)) -> (f32, f32) {
+        let scale = LUMA_SCALE as f32;
+        let offset = (LUMA_SCALE / 2) as f32 + 0.5;
+
+        (
+            (point.0 - 0.5) * scale + offset,
+            (point.1 - 0.5) * scale + offset,
+        )
+    }
+}
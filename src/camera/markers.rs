@@ -0,0 +1,747 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::camera::CameraModel;
+use crate::camera::linalg::{fit_homography, pose_from_homography, transform};
+use crate::camera::luma::LumaImage;
+use crate::config::Config;
+
+const MARKERS_FILE: &str = "markers.conf";
+
+// Cells along each side of the code, and of the whole marker with its black
+// border
+const CODE_CELLS: usize = 4;
+const MARKER_CELLS: usize = CODE_CELLS + 2;
+
+// Fewest bits any two codes differ by in any rotation, which lets a code
+// with a single wrong bit still be read
+const CODE_DISTANCE: u32 = 4;
+
+// Codes in the dictionary, so marker ids are 0 to 99
+const MAX_MARKERS: usize = 100;
+
+// Smallest difference between the black and white cells of a marker, in
+// luma levels
+const MIN_CONTRAST: f32 = 30.0;
+
+// Pixels per cell in printed markers
+const PRINT_CELL_SIZE: usize = 64;
+
+// The code turned a quarter turn clockwise. Bit row * 4 + column is the cell
+// at that row and column, 1 for white
+fn rotate(code: u16) -> u16 {
+    let mut rotated = 0;
+    for row in 0..CODE_CELLS {
+        for column in 0..CODE_CELLS {
+            let bit = (code >> ((CODE_CELLS - 1 - column) * CODE_CELLS + row)) & 1;
+            rotated |= bit << (row * CODE_CELLS + column);
+        }
+    }
+
+    rotated
+}
+
+// A code in each of its four rotations, clockwise
+fn rotations(code: u16) -> [u16; 4] {
+    let mut rotations = [code; 4];
+    for turn in 1..4 {
+        rotations[turn] = rotate(rotations[turn - 1]);
+    }
+
+    rotations
+}
+
+// Marker codes by id, each in all four rotations. The codes are picked
+// greedily in order, so the same ids always get the same codes
+fn dictionary() -> Vec<[u16; 4]> {
+    let mut codes: Vec<[u16; 4]> = Vec::new();
+    for code in 0..=u16::MAX {
+        if codes.len() == MAX_MARKERS {
+            break;
+        }
+
+        // Far from plain black or white squares, which are everywhere
+        let ones = code.count_ones();
+        if !(CODE_DISTANCE..=16 - CODE_DISTANCE).contains(&ones) {
+            continue;
+        }
+
+        // Far from its own rotations, so the way up can be told
+        let turned = rotations(code);
+        if turned[1..].iter().any(|r| (r ^ code).count_ones() < CODE_DISTANCE) {
+            continue;
+        }
+
+        let close = codes.iter().any(|other| {
+            turned
+                .iter()
+                .any(|r| (r ^ other[0]).count_ones() < CODE_DISTANCE)
+        });
+        if !close {
+            codes.push(turned);
+        }
+    }
+
+    codes
+}
+
+// The marker with this code and one white cell of margin all around, as
+// rows of luma values
+fn marker_image(code: u16, cell_size: usize) -> Vec<u8> {
+    let cells = MARKER_CELLS + 2;
+    let size = cells * cell_size;
+    let mut image = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let (column, row) = (x / cell_size, y / cell_size);
+            let white = if column == 0 || row == 0 || column == cells - 1 || row == cells - 1 {
+                true
+            } else if column == 1 || row == 1 || column == cells - 2 || row == cells - 2 {
+                false
+            } else {
+                (code >> ((row - 2) * CODE_CELLS + column - 2)) & 1 == 1
+            };
+            image.push(if white { 255 } else { 0 });
+        }
+    }
+
+    image
+}
+
+// Writes marker `id` as a PGM image with a white margin, ready to print
+pub fn save_marker(id: u16) -> std::io::Result<()> {
+    let code = dictionary()
+        .get(usize::from(id))
+        .map(|rotations| rotations[0])
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("marker ids go from 0 to {}", MAX_MARKERS - 1),
+            )
+        })?;
+
+    let size = (MARKER_CELLS + 2) * PRINT_CELL_SIZE;
+    let mut image = format!("P5\n{} {}\n255\n", size, size).into_bytes();
+    image.extend(marker_image(code, PRINT_CELL_SIZE));
+
+    let path = format!("marker_{}.pgm", id);
+    std::fs::write(&path, image)?;
+    println!("Saved marker {} to {}", id, path);
+
+    Ok(())
+}
+
+// Where a marker is relative to the camera
+#[derive(Clone, Copy)]
+pub struct MarkerPose {
+    // Centimetres right, down and forward of the camera to the marker's
+    // middle
+    pub position: (f32, f32, f32),
+    // How far the marker is turned from facing the camera square on, in
+    // degrees, positive when its right edge is further away
+    pub yaw: f32,
+}
+
+impl MarkerPose {
+    pub fn distance(&self) -> f32 {
+        let (x, y, z) = self.position;
+        (x * x + y * y + z * z).sqrt()
+    }
+}
+
+#[derive(Clone)]
+pub struct Marker {
+    pub id: u16,
+    // Name given to the id in markers.conf
+    pub label: Option<String>,
+    // Upright frame coordinates of the outside of the black border,
+    // clockwise from the marker's own top left
+    pub corners: [(f32, f32); 4],
+    // Code bits that were read wrong and corrected, 0 or 1
+    pub errors: u32,
+    pub pose: Option<MarkerPose>,
+}
+
+impl Marker {
+    pub fn center(&self) -> (f32, f32) {
+        let sum = self
+            .corners
+            .iter()
+            .fold((0.0, 0.0), |sum, c| (sum.0 + c.0, sum.1 + c.1));
+        (sum.0 / 4.0, sum.1 / 4.0)
+    }
+
+    pub fn coordinate(&self) -> (usize, usize) {
+        let (x, y) = self.center();
+        (x.max(0.0) as usize, y.max(0.0) as usize)
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (x, y) = self.center();
+        write!(f, "Marker {}", self.id)?;
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        write!(f, " at ({:.0}, {:.0})", x, y)?;
+        if let Some(pose) = &self.pose {
+            write!(f, ", {:.0} cm away, turned {:.0}°", pose.distance(), pose.yaw)?;
+        }
+        if self.errors > 0 {
+            write!(f, ", {} bit corrected", self.errors)?;
+        }
+
+        Ok(())
+    }
+}
+
+// markers.conf:
+//
+// size = 10
+// threshold_radius = 15
+// threshold_offset = 10
+// min_side = 12
+//
+// [dock]
+// id = 3
+//
+// The size is the side of the black square in centimetres, the rest are in
+// luma image pixels
+pub struct MarkerSettings {
+    size: f32,
+    // Half the side of the window each pixel is compared with to decide
+    // whether it is dark
+    threshold_radius: usize,
+    // How much darker than the window's average a dark pixel is
+    threshold_offset: u8,
+    // Shortest marker side looked at
+    min_side: f32,
+    labels: HashMap<u16, String>,
+}
+
+impl Default for MarkerSettings {
+    fn default() -> Self {
+        Self {
+            size: 10.0,
+            threshold_radius: 15,
+            threshold_offset: 10,
+            min_side: 12.0,
+            labels: HashMap::new(),
+        }
+    }
+}
+
+impl MarkerSettings {
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+
+        let mut labels = HashMap::new();
+        for name in config.section_names().filter(|n| !n.is_empty()) {
+            let id: u16 = config.parse_value(name, "id")?;
+            if usize::from(id) >= MAX_MARKERS {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("marker ids go from 0 to {}, not {}", MAX_MARKERS - 1, id),
+                ));
+            }
+            labels.insert(id, name.to_string());
+        }
+
+        let settings = Self {
+            size: config.parse_or("", "size", defaults.size)?,
+            threshold_radius: config.parse_or("", "threshold_radius", defaults.threshold_radius)?,
+            threshold_offset: config.parse_or("", "threshold_offset", defaults.threshold_offset)?,
+            min_side: config.parse_or("", "min_side", defaults.min_side)?,
+            labels,
+        };
+
+        if settings.size <= 0.0 || settings.threshold_radius == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the marker size and threshold radius must be above 0",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(MARKERS_FILE, "marker settings", Self::parse)
+    }
+
+    // The id of a marker named in markers.conf, or given as a number
+    pub fn id(&self, name: &str) -> Option<u16> {
+        let labelled = self.labels.iter().find(|(_, label)| *label == name);
+        match labelled {
+            Some((&id, _)) => Some(id),
+            None => name.parse().ok().filter(|&id| usize::from(id) < MAX_MARKERS),
+        }
+    }
+}
+
+// Pixels darker than their surroundings, which copes with uneven lighting
+// where a single threshold wouldn't
+fn threshold(luma: &LumaImage, radius: usize, offset: u8) -> Vec<bool> {
+    let (width, height) = luma.dimensions();
+
+    // Sums of every pixel above and to the left, with a zero row and column
+    let mut integral = vec![0u32; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row = 0;
+        for x in 0..width {
+            row += u32::from(luma.get(x, y));
+            integral[(y + 1) * (width + 1) + x + 1] = integral[y * (width + 1) + x + 1] + row;
+        }
+    }
+
+    let mut dark = vec![false; width * height];
+    for y in 0..height {
+        let (top, bottom) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+        for x in 0..width {
+            let (left, right) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+            let sum = integral[bottom * (width + 1) + right] + integral[top * (width + 1) + left]
+                - integral[top * (width + 1) + right]
+                - integral[bottom * (width + 1) + left];
+            let count = ((bottom - top) * (right - left)) as u32;
+
+            let value = u32::from(luma.get(x, y)) + u32::from(offset);
+            dark[y * width + x] = value * count < sum;
+        }
+    }
+
+    dark
+}
+
+// For each 4-connected region of dark pixels, the leftmost and rightmost
+// pixel of each of its rows, which is all its convex hull needs. Regions
+// touching the edge of the image are left out, a marker there is cut off
+fn dark_regions(dark: &[bool], dimensions: (usize, usize)) -> Vec<Vec<(usize, usize)>> {
+    let (width, height) = dimensions;
+    let mut seen = vec![false; dark.len()];
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..dark.len() {
+        if !dark[start] || seen[start] {
+            continue;
+        }
+
+        seen[start] = true;
+        stack.push(start);
+
+        // Leftmost and rightmost x by row
+        let mut rows: HashMap<usize, (usize, usize)> = HashMap::new();
+        let mut on_edge = false;
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            on_edge |= x == 0 || y == 0 || x == width - 1 || y == height - 1;
+
+            let extent = rows.entry(y).or_insert((x, x));
+            *extent = (extent.0.min(x), extent.1.max(x));
+
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then(|| index + 1),
+                (y > 0).then(|| index - width),
+                (y + 1 < height).then(|| index + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if dark[neighbour] && !seen[neighbour] {
+                    seen[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        if !on_edge {
+            regions.push(
+                rows.into_iter()
+                    .flat_map(|(y, (left, right))| [(left, y), (right, y)])
+                    .collect(),
+            );
+        }
+    }
+
+    regions
+}
+
+fn cross(o: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+// Convex hull by the monotone chain, clockwise in image coordinates
+fn convex_hull(mut points: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+    points.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let mut hull: Vec<(f32, f32)> = Vec::with_capacity(points.len() * 2);
+    for pass in 0..2 {
+        let start = hull.len();
+        let ordered: Box<dyn Iterator<Item = &(f32, f32)>> = if pass == 0 {
+            Box::new(points.iter())
+        } else {
+            Box::new(points.iter().rev())
+        };
+
+        for &point in ordered {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        hull.pop();
+    }
+
+    hull
+}
+
+fn polygon_area(points: &[(f32, f32)]) -> f32 {
+    let mut area = 0.0;
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        area += a.0 * b.1 - b.0 * a.1;
+    }
+
+    area.abs() / 2.0
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+// The four corners of a region that is close enough to a quadrilateral,
+// clockwise in image coordinates. The first diagonal is the two furthest
+// apart points, the other two corners are the furthest either side of it
+fn fit_quad(hull: &[(f32, f32)], min_side: f32) -> Option<[(f32, f32); 4]> {
+    if hull.len() < 4 {
+        return None;
+    }
+
+    let mut diagonal = (0, 0, 0.0);
+    for (i, &a) in hull.iter().enumerate() {
+        for (j, &b) in hull.iter().enumerate().skip(i + 1) {
+            let length = distance(a, b);
+            if length > diagonal.2 {
+                diagonal = (i, j, length);
+            }
+        }
+    }
+
+    let (a, c) = (hull[diagonal.0], hull[diagonal.1]);
+    let side = |sign: f32| {
+        hull.iter()
+            .copied()
+            .max_by(|&p, &q| (sign * cross(a, c, p)).total_cmp(&(sign * cross(a, c, q))))
+    };
+    let (b, d) = (side(1.0)?, side(-1.0)?);
+    if cross(a, c, b) <= 0.0 || cross(a, c, d) >= 0.0 {
+        return None;
+    }
+
+    let mut corners = [a, b, c, d];
+
+    // Round things and blobs cover their hull but not its quadrilateral
+    if polygon_area(&corners) < 0.9 * polygon_area(hull) {
+        return None;
+    }
+
+    if (0..4).any(|i| distance(corners[i], corners[(i + 1) % 4]) < min_side) {
+        return None;
+    }
+
+    // Points were pixel middles, move the corners out to the pixels' edges
+    let middle = (
+        corners.iter().map(|c| c.0).sum::<f32>() / 4.0,
+        corners.iter().map(|c| c.1).sum::<f32>() / 4.0,
+    );
+    for corner in &mut corners {
+        let (dx, dy) = (corner.0 - middle.0, corner.1 - middle.1);
+        let length = dx.hypot(dy);
+        *corner = (
+            corner.0 + 0.5 + dx / length * 0.7,
+            corner.1 + 0.5 + dy / length * 0.7,
+        );
+    }
+
+    // Clockwise is increasing angle when y points down
+    corners.sort_by(|p, q| {
+        (p.1 - middle.1)
+            .atan2(p.0 - middle.0)
+            .total_cmp(&(q.1 - middle.1).atan2(q.0 - middle.0))
+    });
+
+    Some(corners)
+}
+
+// Reads the cells of a marker seen at these corners, None if it doesn't have
+// a black border and enough contrast to be one
+fn read_code(luma: &LumaImage, corners: &[(f32, f32); 4]) -> Option<u16> {
+    let size = MARKER_CELLS as f64;
+    let square = [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)];
+    let image: Vec<(f64, f64)> = corners
+        .iter()
+        .map(|c| (f64::from(c.0), f64::from(c.1)))
+        .collect();
+    let homography = fit_homography(&square, &image)?;
+
+    // The middle half of each cell, away from blurred edges
+    let mut cells = [[0.0f32; MARKER_CELLS]; MARKER_CELLS];
+    for (row, cells_row) in cells.iter_mut().enumerate() {
+        for (column, cell) in cells_row.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for sub_y in 0..3 {
+                for sub_x in 0..3 {
+                    let point = (
+                        column as f64 + 0.25 + sub_x as f64 * 0.25,
+                        row as f64 + 0.25 + sub_y as f64 * 0.25,
+                    );
+                    let (x, y) = transform(&homography, point)?;
+                    sum += luma.sample((x as f32, y as f32))?;
+                }
+            }
+            *cell = sum / 9.0;
+        }
+    }
+
+    let values = cells.iter().flatten();
+    let darkest = values.clone().copied().fold(f32::MAX, f32::min);
+    let brightest = values.copied().fold(f32::MIN, f32::max);
+    if brightest - darkest < MIN_CONTRAST {
+        return None;
+    }
+    let middle = (darkest + brightest) / 2.0;
+
+    let mut code = 0;
+    for (row, cells_row) in cells.iter().enumerate() {
+        for (column, &value) in cells_row.iter().enumerate() {
+            let last = MARKER_CELLS - 1;
+            if row == 0 || column == 0 || row == last || column == last {
+                if value > middle {
+                    return None;
+                }
+            } else if value > middle {
+                code |= 1 << ((row - 1) * CODE_CELLS + column - 1);
+            }
+        }
+    }
+
+    Some(code)
+}
+
+// Finds square markers in a frame's luma image
+pub struct MarkerDetector {
+    settings: MarkerSettings,
+    dictionary: Vec<[u16; 4]>,
+    model: CameraModel,
+}
+
+impl MarkerDetector {
+    pub fn new(settings: MarkerSettings, model: &CameraModel) -> Self {
+        Self {
+            settings,
+            dictionary: dictionary(),
+            model: model.clone(),
+        }
+    }
+
+    // The id, quarter turns clockwise and wrong bits of the closest code
+    fn identify(&self, code: u16) -> Option<(u16, usize, u32)> {
+        let mut best: Option<(u16, usize, u32)> = None;
+        for (id, rotations) in self.dictionary.iter().enumerate() {
+            for (turns, rotated) in rotations.iter().enumerate() {
+                let errors = (code ^ rotated).count_ones();
+                if best.is_none_or(|b| errors < b.2) {
+                    best = Some((id as u16, turns, errors));
+                }
+            }
+        }
+
+        best.filter(|b| b.2 < CODE_DISTANCE / 2)
+    }
+
+    // Pose from the corners in the upright frame, with the camera model's
+    // focal lengths
+    fn pose(&self, corners: &[(f32, f32); 4], dimensions: (usize, usize)) -> Option<MarkerPose> {
+        let size = f64::from(self.settings.size);
+        let square = [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)];
+        let image: Vec<(f64, f64)> = corners
+            .iter()
+            .map(|c| (f64::from(c.0), f64::from(c.1)))
+            .collect();
+        let homography = fit_homography(&square, &image)?;
+
+        let (fx, fy) = self.model.focal_lengths(dimensions);
+        let center = (dimensions.0 as f64 / 2.0, dimensions.1 as f64 / 2.0);
+        let (rotation, translation) =
+            pose_from_homography(&homography, (f64::from(fx), f64::from(fy)), center);
+
+        // From the top left corner to the middle of the marker
+        let middle = [0, 1, 2].map(|i| {
+            translation[i] + (rotation[i][0] + rotation[i][1]) * size / 2.0
+        });
+        let normal = [rotation[0][2], rotation[1][2], rotation[2][2]];
+
+        Some(MarkerPose {
+            position: (middle[0] as f32, middle[1] as f32, middle[2] as f32),
+            yaw: (-normal[0]).atan2(normal[2]).to_degrees() as f32,
+        })
+    }
+
+    // Every marker in the luma image. `correct` moves a point of the
+    // upright frame to where it would be without lens distortion, for when
+    // the frame itself wasn't undistorted
+    pub fn detect(
+        &self,
+        luma: &LumaImage,
+        dimensions: (usize, usize),
+        correct: impl Fn((f32, f32)) -> (f32, f32),
+    ) -> Vec<Marker> {
+        let dark = threshold(luma, self.settings.threshold_radius, self.settings.threshold_offset);
+
+        let mut markers = Vec::new();
+        for region in dark_regions(&dark, luma.dimensions()) {
+            // Too few rows to be a marker
+            if (region.len() as f32) < self.settings.min_side * 2.0 {
+                continue;
+            }
+
+            let points = region.iter().map(|&(x, y)| (x as f32, y as f32)).collect();
+            let Some(corners) = fit_quad(&convex_hull(points), self.settings.min_side) else {
+                continue;
+            };
+
+            let Some((id, turns, errors)) = read_code(luma, &corners).and_then(|c| self.identify(c))
+            else {
+                continue;
+            };
+
+            // Start from the marker's own top left, which the turns moved
+            let corners =
+                [0, 1, 2, 3].map(|n| correct(LumaImage::to_frame(corners[(n + turns) % 4])));
+
+            markers.push(Marker {
+                id,
+                label: self.settings.labels.get(&id).cloned(),
+                corners,
+                errors,
+                pose: self.pose(&corners, dimensions),
+            });
+        }
+
+        markers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn four_turns_are_the_identity() {
+        for code in 0..=u16::MAX {
+            assert_eq!(rotations(rotate(code))[3], code);
+        }
+    }
+
+    #[test]
+    fn dictionary_is_full() {
+        assert_eq!(dictionary().len(), MAX_MARKERS);
+    }
+
+    #[test]
+    fn codes_are_far_from_their_own_rotations() {
+        for turned in dictionary() {
+            for r in &turned[1..] {
+                assert!((r ^ turned[0]).count_ones() >= CODE_DISTANCE);
+            }
+        }
+    }
+
+    #[test]
+    fn codes_are_far_from_each_other_in_every_rotation() {
+        let codes = dictionary();
+        for (i, a) in codes.iter().enumerate() {
+            for b in &codes[i + 1..] {
+                for (ra, rb) in a.iter().zip(b) {
+                    assert!((ra ^ b[0]).count_ones() >= CODE_DISTANCE);
+                    assert!((ra ^ rb).count_ones() >= CODE_DISTANCE);
+                }
+            }
+        }
+    }
+
+    // Marker `id` printed with 8 pixel cells on a grey background, turned
+    // `turns` quarter turns clockwise, with the black square from (48, 38) to
+    // (96, 86)
+    fn rendered(id: usize, turns: usize) -> LumaImage {
+        let cell = 8;
+        let size = (MARKER_CELLS + 2) * cell;
+        let mut marker = marker_image(dictionary()[id][0], cell);
+        for _ in 0..turns {
+            let turned = (0..size * size).map(|i| {
+                let (x, y) = (i % size, i / size);
+                marker[(size - 1 - x) * size + y]
+            });
+            marker = turned.collect();
+        }
+
+        let (width, height) = (160, 120);
+        let mut pixels = vec![128; width * height];
+        for y in 0..size {
+            for x in 0..size {
+                pixels[(y + 30) * width + x + 40] = marker[y * size + x];
+            }
+        }
+
+        LumaImage::new(pixels, (width, height))
+    }
+
+    fn assert_corners(marker: &Marker, expected: [(f32, f32); 4]) {
+        for (corner, expected) in marker.corners.iter().zip(expected) {
+            let expected = LumaImage::to_frame(expected);
+            assert!(
+                distance(*corner, expected) < 1.5,
+                "corner {:?} should be near {:?}",
+                corner,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn detects_a_printed_marker() {
+        let detector = MarkerDetector::new(MarkerSettings::default(), &CameraModel::default());
+        let markers = detector.detect(&rendered(3, 0), (320, 240), |p| p);
+
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].id, 3);
+        assert_eq!(markers[0].errors, 0);
+        assert_corners(&markers[0], [(48.0, 38.0), (96.0, 38.0), (96.0, 86.0), (48.0, 86.0)]);
+    }
+
+    #[test]
+    fn corners_start_from_a_turned_markers_own_top_left() {
+        let detector = MarkerDetector::new(MarkerSettings::default(), &CameraModel::default());
+        let markers = detector.detect(&rendered(42, 1), (320, 240), |p| p);
+
+        // A quarter turn clockwise takes its top left to the top right
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].id, 42);
+        assert_corners(&markers[0], [(96.0, 38.0), (96.0, 86.0), (48.0, 86.0), (48.0, 38.0)]);
+    }
+
+    #[test]
+    fn codes_are_far_from_plain_squares() {
+        for turned in dictionary() {
+            let ones = turned[0].count_ones();
+            assert!((CODE_DISTANCE..=16 - CODE_DISTANCE).contains(&ones));
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::camera::orientation::Orientation;
+use crate::config::Config;
+
+const MODEL_FILE: &str = "camera_model.conf";
+
+// A direction from the camera, in the robot's body frame
+#[derive(Clone, Copy)]
+pub struct Bearing {
+    // Degrees to the right of straight ahead, negative to the left
+    pub azimuth: f32,
+    // Degrees above level, negative below
+    pub elevation: f32,
+}
+
+impl fmt::Display for Bearing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1}° azimuth, {:.1}° elevation", self.azimuth, self.elevation)
+    }
+}
+
+// How pixels relate to directions around the robot
+#[derive(Clone)]
+pub struct CameraModel {
+    // Full horizontal and vertical field of view of the upright image, in
+    // degrees
+    fov: (f32, f32),
+    // Extra yaw (to the right) and pitch (up) of the camera on its mount, in
+    // degrees, for a camera that isn't quite straight with the servos centred
+    mount: (f32, f32),
+    // Pan servo angle at which the camera looks straight ahead, and whether
+    // larger angles turn it right rather than left
+    pan_center: f32,
+    pan_reversed: bool,
+    // Tilt servo angle at which the camera looks level, and whether larger
+    // angles tilt it down rather than up
+    tilt_level: f32,
+    tilt_reversed: bool,
+    orientation: Orientation,
+}
+
+impl Default for CameraModel {
+    fn default() -> Self {
+        Self {
+            fov: (60.0, 34.0),
+            mount: (0.0, 0.0),
+            pan_center: 90.0,
+            pan_reversed: false,
+            tilt_level: 90.0,
+            tilt_reversed: false,
+            orientation: Orientation::default(),
+        }
+    }
+}
+
+impl CameraModel {
+    // camera_model.conf:
+    //
+    // horizontal_fov = 60
+    // vertical_fov = 34
+    // mount_yaw = 0
+    // mount_pitch = 0
+    // pan_center = 90
+    // pan_reversed = false
+    // tilt_level = 90
+    // tilt_reversed = false
+    // rotation = 0
+    // mirror_horizontal = false
+    // mirror_vertical = false
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let model = Self {
+            fov: (
+                config.parse_or("", "horizontal_fov", defaults.fov.0)?,
+                config.parse_or("", "vertical_fov", defaults.fov.1)?,
+            ),
+            mount: (
+                config.parse_or("", "mount_yaw", defaults.mount.0)?,
+                config.parse_or("", "mount_pitch", defaults.mount.1)?,
+            ),
+            pan_center: config.parse_or("", "pan_center", defaults.pan_center)?,
+            pan_reversed: config.parse_or("", "pan_reversed", defaults.pan_reversed)?,
+            tilt_level: config.parse_or("", "tilt_level", defaults.tilt_level)?,
+            tilt_reversed: config.parse_or("", "tilt_reversed", defaults.tilt_reversed)?,
+            orientation: Orientation::parse(config, "")?,
+        };
+
+        let valid = |fov: f32| fov > 0.0 && fov < 180.0;
+        if !valid(model.fov.0) || !valid(model.fov.1) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the field of view must be between 0 and 180 degrees",
+            ));
+        }
+
+        Ok(model)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(MODEL_FILE, "camera model", Self::parse)
+    }
+
+    // How frames are turned upright, applied by the pipeline so that every
+    // frame coordinate is already upright
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    // Focal lengths in pixels for a frame of these dimensions
+    pub fn focal_lengths(&self, dimensions: (usize, usize)) -> (f32, f32) {
+        (
+            dimensions.0 as f32 / 2.0 / (self.fov.0 / 2.0).to_radians().tan(),
+            dimensions.1 as f32 / 2.0 / (self.fov.1 / 2.0).to_radians().tan(),
+        )
+    }
+
+    // Yaw (to the right) and pitch (up) of the camera in radians, with
+    // unknown servo angles taken to be centred
+    fn angles(&self, pan: Option<u8>, tilt: Option<u8>) -> (f32, f32) {
+        let servo = |angle: Option<u8>, center: f32, reversed: bool| {
+            let offset = angle.map_or(0.0, |a| f32::from(a) - center);
+            if reversed { -offset } else { offset }
+        };
+
+        // Pan turns the camera left as the angle grows, unless reversed
+        (
+            (self.mount.0 - servo(pan, self.pan_center, self.pan_reversed)).to_radians(),
+            (self.mount.1 + servo(tilt, self.tilt_level, self.tilt_reversed)).to_radians(),
+        )
+    }
+
+    // The ray through a point of the upright frame as (forward, right, up) in
+    // the robot's body frame. Points are continuous, pixel (0, 0) covers
+    // (0, 0) to (1, 1)
+    fn ray(
+        &self,
+        point: (f32, f32),
+        dimensions: (usize, usize),
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> (f32, f32, f32) {
+        let (fx, fy) = self.focal_lengths(dimensions);
+        let right = (point.0 - dimensions.0 as f32 / 2.0) / fx;
+        let up = (dimensions.1 as f32 / 2.0 - point.1) / fy;
+
+        let (yaw, pitch) = self.angles(pan, tilt);
+
+        // Tilt about the camera's right axis, then pan about the vertical
+        let (forward, up) = (
+            pitch.cos() - up * pitch.sin(),
+            pitch.sin() + up * pitch.cos(),
+        );
+        let (forward, right) = (
+            forward * yaw.cos() - right * yaw.sin(),
+            forward * yaw.sin() + right * yaw.cos(),
+        );
+
+        (forward, right, up)
+    }
+
+    // Where a body frame ray shows up in the frame, the inverse of ray. None
+    // if it is behind the camera
+    fn project(
+        &self,
+        ray: (f32, f32, f32),
+        dimensions: (usize, usize),
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> Option<(f32, f32)> {
+        let (forward, right, up) = ray;
+        let (yaw, pitch) = self.angles(pan, tilt);
+
+        let (forward, right) = (
+            forward * yaw.cos() + right * yaw.sin(),
+            right * yaw.cos() - forward * yaw.sin(),
+        );
+        let (forward, up) = (
+            forward * pitch.cos() + up * pitch.sin(),
+            up * pitch.cos() - forward * pitch.sin(),
+        );
+
+        if forward <= f32::EPSILON {
+            return None;
+        }
+
+        let (fx, fy) = self.focal_lengths(dimensions);
+        Some((
+            dimensions.0 as f32 / 2.0 + fx * right / forward,
+            dimensions.1 as f32 / 2.0 - fy * up / forward,
+        ))
+    }
+
+    // Where a point seen with the servos at `from` would have been seen with
+    // them at `to`, as (pan, tilt) angles
+    pub fn reproject(
+        &self,
+        point: (f32, f32),
+        dimensions: (usize, usize),
+        from: (Option<u8>, Option<u8>),
+        to: (Option<u8>, Option<u8>),
+    ) -> Option<(f32, f32)> {
+        let ray = self.ray(point, dimensions, from.0, from.1);
+        self.project(ray, dimensions, to.0, to.1)
+    }
+
+    // The direction of an (upright) frame coordinate, given the servo angles
+    // the camera is at. Unknown servo angles are taken to be centred
+    pub fn bearing(
+        &self,
+        coordinate: (usize, usize),
+        dimensions: (usize, usize),
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> Bearing {
+        // Through the middle of the pixel
+        let point = (coordinate.0 as f32 + 0.5, coordinate.1 as f32 + 0.5);
+        self.point_bearing(point, dimensions, pan, tilt)
+    }
+
+    // bearing for a continuous point
+    pub fn point_bearing(
+        &self,
+        point: (f32, f32),
+        dimensions: (usize, usize),
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> Bearing {
+        let (forward, right, up) = self.ray(point, dimensions, pan, tilt);
+
+        Bearing {
+            azimuth: right.atan2(forward).to_degrees(),
+            elevation: up.atan2(forward.hypot(right)).to_degrees(),
+        }
+    }
+
+    // How far the middle of the frame moves when the robot turns clockwise
+    // by `yaw` degrees, None if it turns out of view
+    pub fn yaw_shift(
+        &self,
+        yaw: f32,
+        dimensions: (usize, usize),
+        pan: Option<u8>,
+        tilt: Option<u8>,
+    ) -> Option<(f32, f32)> {
+        let center = (dimensions.0 as f32 / 2.0, dimensions.1 as f32 / 2.0);
+        let (forward, right, up) = self.ray(center, dimensions, pan, tilt);
+
+        // Everything around the robot turns the other way
+        let yaw = yaw.to_radians();
+        let ray = (
+            forward * yaw.cos() + right * yaw.sin(),
+            right * yaw.cos() - forward * yaw.sin(),
+            up,
+        );
+
+        let (x, y) = self.project(ray, dimensions, pan, tilt)?;
+        Some((x - center.0, y - center.1))
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::camera::color::NO_CLASS;
+use crate::config::Config;
+
+const MASK_FILE: &str = "mask.conf";
+
+// Largest supported downsampling, beyond this targets are only a few
+// mask pixels across
+const MAX_SCALE: usize = 16;
+
+// Largest supported filter radius
+const MAX_RADIUS: usize = 4;
+
+// One step of noise filtering over the class mask. Each takes a radius, the
+// window being a square of 2 * radius + 1 mask pixels
+#[derive(Clone, Copy)]
+pub enum MaskOperation {
+    // Shrinks every region, removing specks smaller than the window
+    Erode(usize),
+    // Grows every region into unclassified pixels, filling small holes
+    Dilate(usize),
+    // Erode then dilate, removing specks while keeping larger regions'
+    // size
+    Open(usize),
+    // Dilate then erode, filling holes and gaps while keeping larger
+    // regions' size
+    Close(usize),
+    // Replaces every pixel with the most common class around it, the
+    // equivalent of a median for class labels
+    Median(usize),
+}
+
+impl FromStr for MaskOperation {
+    type Err = ();
+
+    // `open 2`, or just `open` for a radius of 1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let name = parts.next().ok_or(())?;
+        let radius = match parts.next() {
+            Some(r) => r.parse().map_err(|_| ())?,
+            None => 1,
+        };
+
+        if parts.next().is_some() || radius == 0 || radius > MAX_RADIUS {
+            return Err(());
+        }
+
+        match name {
+            "erode" => Ok(MaskOperation::Erode(radius)),
+            "dilate" => Ok(MaskOperation::Dilate(radius)),
+            "open" => Ok(MaskOperation::Open(radius)),
+            "close" => Ok(MaskOperation::Close(radius)),
+            "median" => Ok(MaskOperation::Median(radius)),
+            _ => Err(()),
+        }
+    }
+}
+
+// How the class mask is built and cleaned up before any statistics are
+// taken from it
+pub struct MaskFilter {
+    // Only one pixel in each scale x scale block is classified
+    scale: usize,
+    // Applied in order
+    operations: Vec<MaskOperation>,
+}
+
+impl Default for MaskFilter {
+    // Every pixel, unfiltered
+    fn default() -> Self {
+        Self {
+            scale: 1,
+            operations: Vec::new(),
+        }
+    }
+}
+
+impl MaskFilter {
+    // mask.conf:
+    //
+    // scale = 4
+    // filters = median 1, open 1, close 2
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let scale = config.parse_or("", "scale", 1)?;
+        if scale == 0 || scale > MAX_SCALE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("mask scale must be between 1 and {}", MAX_SCALE),
+            ));
+        }
+
+        let mut operations = Vec::new();
+        if let Some(filters) = config.get("", "filters") {
+            for filter in filters.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+                operations.push(filter.parse().map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid mask filter `{}`", filter),
+                    )
+                })?);
+            }
+        }
+
+        Ok(Self { scale, operations })
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(MASK_FILE, "mask filter", Self::parse)
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    pub fn apply(&self, mask: &[u8], dimensions: (usize, usize)) -> Vec<u8> {
+        let mut mask = mask.to_vec();
+        for operation in &self.operations {
+            mask = match *operation {
+                MaskOperation::Erode(r) => erode(&mask, dimensions, r),
+                MaskOperation::Dilate(r) => dilate(&mask, dimensions, r),
+                MaskOperation::Open(r) => dilate(&erode(&mask, dimensions, r), dimensions, r),
+                MaskOperation::Close(r) => erode(&dilate(&mask, dimensions, r), dimensions, r),
+                MaskOperation::Median(r) => median(&mask, dimensions, r),
+            };
+        }
+
+        mask
+    }
+}
+
+// Collects the window around a pixel, clipped to the mask
+fn window(
+    mask: &[u8],
+    dimensions: (usize, usize),
+    x: usize,
+    y: usize,
+    radius: usize,
+    values: &mut Vec<u8>,
+) {
+    values.clear();
+
+    let (width, height) = dimensions;
+    let columns = (x.saturating_sub(radius), (x + radius + 1).min(width));
+    for wy in y.saturating_sub(radius)..(y + radius + 1).min(height) {
+        let row = wy * width;
+        values.extend_from_slice(&mask[row + columns.0..row + columns.1]);
+    }
+}
+
+// The most common value, preferring `current` in a tie so regions don't shift
+fn most_common(values: &mut [u8], current: u8) -> u8 {
+    values.sort_unstable();
+
+    let mut best = (current, values.iter().filter(|&&v| v == current).count());
+    for run in values.chunk_by(|a, b| a == b) {
+        if run.len() > best.1 {
+            best = (run[0], run.len());
+        }
+    }
+
+    best.0
+}
+
+fn filter(
+    mask: &[u8],
+    dimensions: (usize, usize),
+    radius: usize,
+    mut pick: impl FnMut(u8, &mut Vec<u8>) -> u8,
+) -> Vec<u8> {
+    let mut values = Vec::with_capacity((2 * radius + 1).pow(2));
+    let mut filtered = Vec::with_capacity(mask.len());
+
+    for y in 0..dimensions.1 {
+        for x in 0..dimensions.0 {
+            window(mask, dimensions, x, y, radius, &mut values);
+            filtered.push(pick(mask[y * dimensions.0 + x], &mut values));
+        }
+    }
+
+    filtered
+}
+
+// A pixel keeps its class only if the whole window has it
+fn erode(mask: &[u8], dimensions: (usize, usize), radius: usize) -> Vec<u8> {
+    filter(mask, dimensions, radius, |current, values| {
+        if values.iter().all(|&v| v == current) {
+            current
+        } else {
+            NO_CLASS
+        }
+    })
+}
+
+// Unclassified pixels take the most common class around them
+fn dilate(mask: &[u8], dimensions: (usize, usize), radius: usize) -> Vec<u8> {
+    filter(mask, dimensions, radius, |current, values| {
+        if current != NO_CLASS {
+            return current;
+        }
+
+        values.retain(|&v| v != NO_CLASS);
+        most_common(values, current)
+    })
+}
+
+fn median(mask: &[u8], dimensions: (usize, usize), radius: usize) -> Vec<u8> {
+    filter(mask, dimensions, radius, |current, values| most_common(values, current))
+}
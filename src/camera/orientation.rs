@@ -0,0 +1,138 @@
+use std::io::{Error, ErrorKind};
+
+use crate::config::Config;
+
+// How the camera image has to be turned to be upright: mirrored first, then
+// rotated clockwise
+#[derive(Clone, Copy, Default)]
+pub struct Orientation {
+    // Clockwise, in quarter turns
+    quarter_turns: u8,
+    mirror_horizontal: bool,
+    mirror_vertical: bool,
+}
+
+impl Orientation {
+    // rotation = 0, 90, 180 or 270
+    // mirror_horizontal = false
+    // mirror_vertical = false
+    pub fn parse(config: &Config, section: &str) -> std::io::Result<Self> {
+        let rotation: u16 = config.parse_or(section, "rotation", 0)?;
+        if !rotation.is_multiple_of(90) || rotation >= 360 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("rotation must be 0, 90, 180 or 270, not {}", rotation),
+            ));
+        }
+
+        Ok(Self {
+            quarter_turns: (rotation / 90) as u8,
+            mirror_horizontal: config.parse_or(section, "mirror_horizontal", false)?,
+            mirror_vertical: config.parse_or(section, "mirror_vertical", false)?,
+        })
+    }
+
+    // Dimensions of the upright image
+    pub fn dimensions(&self, dimensions: (usize, usize)) -> (usize, usize) {
+        if self.quarter_turns % 2 == 1 {
+            (dimensions.1, dimensions.0)
+        } else {
+            dimensions
+        }
+    }
+
+    // Where a pixel of the upright image came from in the raw one. The
+    // dimensions are the raw image's
+    pub fn raw(&self, coordinate: (usize, usize), dimensions: (usize, usize)) -> (usize, usize) {
+        let (width, height) = dimensions;
+        let (x, y) = coordinate;
+
+        // Undo the rotation, then the mirroring
+        let (mut x, mut y) = match self.quarter_turns {
+            1 => (y, height - 1 - x),
+            2 => (width - 1 - x, height - 1 - y),
+            3 => (width - 1 - y, x),
+            _ => (x, y),
+        };
+
+        if self.mirror_horizontal {
+            x = width - 1 - x;
+        }
+        if self.mirror_vertical {
+            y = height - 1 - y;
+        }
+
+        (x, y)
+    }
+
+    // Where a continuous point of the raw image ends up in the upright one,
+    // the inverse of raw
+    pub fn upright_point(&self, point: (f32, f32), dimensions: (usize, usize)) -> (f32, f32) {
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+        let (mut x, mut y) = point;
+
+        if self.mirror_horizontal {
+            x = width - x;
+        }
+        if self.mirror_vertical {
+            y = height - y;
+        }
+
+        match self.quarter_turns {
+            1 => (height - y, x),
+            2 => (width - x, height - y),
+            3 => (y, width - x),
+            _ => (x, y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every rotation with every mirroring, covering all 8 ways the image can
+    // be turned, some of them twice
+    fn all() -> impl Iterator<Item = Orientation> {
+        (0..16u8).map(|i| Orientation {
+            quarter_turns: i % 4,
+            mirror_horizontal: i & 4 != 0,
+            mirror_vertical: i & 8 != 0,
+        })
+    }
+
+    #[test]
+    fn raw_is_a_permutation() {
+        let dimensions = (5, 3);
+        for orientation in all() {
+            let (width, height) = orientation.dimensions(dimensions);
+            let mut seen = vec![false; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let (rx, ry) = orientation.raw((x, y), dimensions);
+                    assert!(rx < dimensions.0 && ry < dimensions.1);
+                    assert!(!seen[ry * dimensions.0 + rx]);
+                    seen[ry * dimensions.0 + rx] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn upright_point_inverts_raw() {
+        let dimensions = (5, 3);
+        for orientation in all() {
+            let (width, height) = orientation.dimensions(dimensions);
+            for y in 0..height {
+                for x in 0..width {
+                    // Through the middle of the raw pixel
+                    let (rx, ry) = orientation.raw((x, y), dimensions);
+                    let point = (rx as f32 + 0.5, ry as f32 + 0.5);
+                    let (ux, uy) = orientation.upright_point(point, dimensions);
+                    assert!((ux - (x as f32 + 0.5)).abs() < 1e-6);
+                    assert!((uy - (y as f32 + 0.5)).abs() < 1e-6);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::camera::{
+    Blob, BlobTarget, CameraModel, CameraVideoStream, ColorClasses, ColorTable, Frame,
+    FrameGeometry, FrameSampler, LensModel, MaskFilter,
+};
+use crate::config::{Config, config_path};
+
+const RANGE_FILE: &str = "range.conf";
+
+// Frames measured during calibration
+const CALIBRATION_FRAMES: usize = 20;
+
+// How the apparent diameter of a blob is measured
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SizeMeasure {
+    // The longer side of the bounding box, which still works for a ball
+    // partly cut off by the edge of the frame
+    Box,
+    // The diameter of a circle of the blob's area, which is steadier for a
+    // ball that is fully in view
+    Area,
+}
+
+impl FromStr for SizeMeasure {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(SizeMeasure::Box),
+            "area" => Ok(SizeMeasure::Area),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SizeMeasure {
+    // In pixels
+    fn apparent_size(&self, blob: &Blob) -> f32 {
+        match self {
+            SizeMeasure::Box => blob.width().max(blob.height()) as f32,
+            SizeMeasure::Area => 2.0 * (blob.area as f32 / PI).sqrt(),
+        }
+    }
+}
+
+// How far away targets of a known size are, from how big they look
+pub struct RangeSettings {
+    // Focal length in pixels and the frame width it was measured at. None
+    // to take it from the camera model's field of view
+    focal_length: Option<(f32, usize)>,
+    measure: SizeMeasure,
+    // Distance actions approach targets to, and how far either side of it
+    // still counts as there, in centimetres
+    pub stop_range: f32,
+    pub tolerance: f32,
+    // Real diameters in centimetres, by class name
+    sizes: HashMap<String, f32>,
+}
+
+impl Default for RangeSettings {
+    fn default() -> Self {
+        Self {
+            focal_length: None,
+            measure: SizeMeasure::Box,
+            stop_range: 20.0,
+            tolerance: 3.0,
+            sizes: HashMap::new(),
+        }
+    }
+}
+
+impl RangeSettings {
+    // range.conf:
+    //
+    // measure = box
+    // stop_range = 20
+    // tolerance = 3
+    // focal_length = 1108
+    // focal_width = 1280
+    //
+    // [red]
+    // diameter = 6.5
+    //
+    // The focal length is written by --calibrate-range
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+
+        let measure = match config.get("", "measure") {
+            None => defaults.measure,
+            Some(m) => m.parse().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("measure must be box or area, not {}", m),
+                )
+            })?,
+        };
+
+        let focal_length = match config.get("", "focal_length") {
+            None => None,
+            Some(_) => Some((
+                config.parse_value("", "focal_length")?,
+                config.parse_value("", "focal_width")?,
+            )),
+        };
+
+        if focal_length.is_some_and(|(focal, width)| focal <= 0.0 || width == 0) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the focal length and width must be above 0",
+            ));
+        }
+
+        let mut sizes = HashMap::new();
+        for name in config.section_names().filter(|n| !n.is_empty()) {
+            let diameter: f32 = config.parse_value(name, "diameter")?;
+            if diameter <= 0.0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("the diameter of {} must be above 0", name),
+                ));
+            }
+            sizes.insert(name.to_string(), diameter);
+        }
+
+        Ok(Self {
+            focal_length,
+            measure,
+            stop_range: config.parse_or("", "stop_range", defaults.stop_range)?,
+            tolerance: config.parse_or("", "tolerance", defaults.tolerance)?,
+            sizes,
+        })
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(RANGE_FILE, "range settings", Self::parse)
+    }
+
+    // Horizontal focal length in pixels for frames of these dimensions
+    fn focal(&self, dimensions: (usize, usize), model: &CameraModel) -> f32 {
+        match self.focal_length {
+            Some((focal, width)) => focal * dimensions.0 as f32 / width as f32,
+            None => model.focal_lengths(dimensions).0,
+        }
+    }
+
+    // Distance to the camera in centimetres of a blob of the named class,
+    // None if the class has no known size
+    pub fn distance(
+        &self,
+        blob: &Blob,
+        class: &str,
+        dimensions: (usize, usize),
+        model: &CameraModel,
+    ) -> Option<f32> {
+        let diameter = self.sizes.get(class)?;
+        let size = self.measure.apparent_size(blob);
+
+        Some(self.focal(dimensions, model) * diameter / size)
+    }
+}
+
+// Measures a target of the named class held at a known distance, and saves
+// the focal length that makes its apparent size come out at that distance
+pub fn calibrate_range(
+    camera_stream: &mut CameraVideoStream,
+    model: &CameraModel,
+    class: &str,
+    distance: f32,
+) -> std::io::Result<()> {
+    let settings = RangeSettings::load();
+    let diameter = *settings.sizes.get(class).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("there is no diameter for {} in {}", class, RANGE_FILE),
+        )
+    })?;
+
+    let classes = Arc::new(ColorClasses::load());
+    let id = classes.find(class).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("there is no color class called {}", class),
+        )
+    })?;
+
+    let color_table = ColorTable::new(classes);
+    let mask_filter = MaskFilter::load();
+    let mut sampler = FrameSampler::new(FrameGeometry::new(model.orientation(), LensModel::load()));
+
+    println!("Measuring the {} target at {} cm", class, distance);
+
+    // Throw away whatever was sitting in the driver's buffers
+    for _ in 0..2 {
+        camera_stream.get_next_jpeg()?;
+    }
+
+    let mut sizes = Vec::new();
+    let mut width = 0;
+    for _ in 0..CALIBRATION_FRAMES {
+        let jpeg = camera_stream.get_next_jpeg()?;
+        let Ok(frame) =
+            Frame::decode(&jpeg, &color_table, &mask_filter, &mut sampler, None, None, (0, 0))
+        else {
+            continue;
+        };
+
+        if let Some(blob) = frame.target_blob(id, BlobTarget::Largest) {
+            sizes.push(settings.measure.apparent_size(blob));
+            width = frame.dimensions().0;
+        }
+    }
+
+    if sizes.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("the {} target wasn't found", class),
+        ));
+    }
+
+    // The median ignores frames where the blob was split or merged
+    sizes.sort_by(f32::total_cmp);
+    let size = sizes[sizes.len() / 2];
+    let focal = size * distance / diameter;
+    println!(
+        "The target looks {:.1} pixels across, a focal length of {:.1} pixels",
+        size, focal
+    );
+
+    // Keep everything else in the file as it was
+    let path = config_path(RANGE_FILE);
+    let mut config = Config::load(&path)?;
+    config.set("", "focal_length", focal);
+    config.set("", "focal_width", width);
+    config.save(&path)?;
+    println!("Saved the focal length to {}", path.display());
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::str::FromStr;
+
+// How pixels are assigned to classes
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClassifierMode {
+    // Closest anchor by plain distance, with a neutral anchor for
+    // uncolored pixels
+    Nearest,
+    // Closest class by Mahalanobis distance using each class's covariance,
+    // rejecting anything outside every class's ellipse
+    Elliptical,
+    // Hue and saturation bands
+    Hsv,
+}
+
+impl FromStr for ClassifierMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(ClassifierMode::Nearest),
+            "elliptical" => Ok(ClassifierMode::Elliptical),
+            "hsv" => Ok(ClassifierMode::Hsv),
+            _ => Err(()),
+        }
+    }
+}
+
+// Spread used when a class has no calibrated covariance
+const DEFAULT_DEVIATION: f32 = 12.0;
+
+// Keeps the covariance invertible for classes calibrated on a flat target
+const MIN_VARIANCE: f32 = 4.0;
+
+// Half width of the default hue band around a class's anchor, in degrees
+const DEFAULT_HUE_BAND: f32 = 30.0;
+
+// The shape of a class in the chroma plane, for the classifiers that need
+// more than an anchor
+#[derive(Clone, Copy)]
+pub struct ChromaRegion {
+    // (uu, uv, vv)
+    pub covariance: (f32, f32, f32),
+    // Mahalanobis distance past which pixels are rejected
+    pub max_distance: f32,
+    // Hue band in degrees, wrapping through 0 when the start is past the end
+    pub hue: (f32, f32),
+    // Minimum HSV saturation, from 0 to 1
+    pub saturation_min: f32,
+}
+
+impl ChromaRegion {
+    // A circular region and a hue band centred on the anchor
+    pub fn around(u: u8, v: u8) -> Self {
+        let hue = chroma_hue(u, v);
+
+        Self {
+            covariance: (DEFAULT_DEVIATION.powi(2), 0.0, DEFAULT_DEVIATION.powi(2)),
+            max_distance: 3.0,
+            hue: (
+                (hue - DEFAULT_HUE_BAND).rem_euclid(360.0),
+                (hue + DEFAULT_HUE_BAND).rem_euclid(360.0),
+            ),
+            saturation_min: 0.25,
+        }
+    }
+
+    pub fn set_covariance(&mut self, covariance: (f32, f32, f32)) {
+        self.covariance = (
+            covariance.0.max(MIN_VARIANCE),
+            covariance.1,
+            covariance.2.max(MIN_VARIANCE),
+        );
+    }
+
+    // Distance from the anchor in standard deviations along the ellipse
+    pub fn mahalanobis(&self, anchor: (u8, u8), u: u8, v: u8) -> f32 {
+        let (uu, uv, vv) = self.covariance;
+        let determinant = (uu * vv - uv * uv).max(f32::EPSILON);
+
+        let du = f32::from(u) - f32::from(anchor.0);
+        let dv = f32::from(v) - f32::from(anchor.1);
+
+        ((vv * du * du - 2.0 * uv * du * dv + uu * dv * dv) / determinant)
+            .max(0.0)
+            .sqrt()
+    }
+
+    // Degrees inside the hue band, negative when outside it
+    pub fn hue_margin(&self, hue: f32) -> f32 {
+        let (start, end) = self.hue;
+        let width = (end - start).rem_euclid(360.0);
+        let offset = (hue - start).rem_euclid(360.0);
+
+        if offset <= width {
+            offset.min(width - offset)
+        } else {
+            -(offset - width).min(360.0 - offset)
+        }
+    }
+}
+
+// Channel offsets from luma for a (u, v) chroma, using the JPEG conversion.
+// Adding luma to each gives the actual RGB value
+fn rgb_offsets(u: u8, v: u8) -> (f32, f32, f32) {
+    let u = f32::from(u) - 128.0;
+    let v = f32::from(v) - 128.0;
+
+    (1.402 * v, -0.344136 * u - 0.714136 * v, 1.772 * u)
+}
+
+// HSV hue in degrees. Adding the same luma to every channel doesn't change it,
+// so it only depends on the chroma
+pub fn chroma_hue(u: u8, v: u8) -> f32 {
+    let (r, g, b) = rgb_offsets(u, v);
+    let max = r.max(g).max(b);
+    let range = max - r.min(g).min(b);
+
+    if range <= f32::EPSILON {
+        return 0.0;
+    }
+
+    let hue = if max == r {
+        (g - b) / range
+    } else if max == g {
+        (b - r) / range + 2.0
+    } else {
+        (r - g) / range + 4.0
+    };
+
+    (hue * 60.0).rem_euclid(360.0)
+}
+
+// The HSV chroma (max - min channel) and the offset of the largest channel
+// from luma. Saturation is chroma / (luma + offset), so only the final
+// division needs the pixel's luma
+pub fn chroma_range(u: u8, v: u8) -> (u8, i16) {
+    let (r, g, b) = rgb_offsets(u, v);
+    let max = r.max(g).max(b);
+    let range = max - r.min(g).min(b);
+
+    (range.round().min(255.0) as u8, max.round() as i16)
+}
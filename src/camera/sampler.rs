@@ -0,0 +1,211 @@
+use crate::camera::lens::{LensModel, Undistortion};
+use crate::camera::luma::LUMA_SCALE;
+use crate::camera::orientation::Orientation;
+
+// How frame coordinates relate to the image the camera sends: turned upright
+// and, once the lens is calibrated, undistorted
+#[derive(Clone, Copy, Default)]
+pub struct FrameGeometry {
+    orientation: Orientation,
+    lens: Option<LensModel>,
+}
+
+impl FrameGeometry {
+    pub fn new(orientation: Orientation, lens: Option<LensModel>) -> Self {
+        Self { orientation, lens }
+    }
+
+    // Dimensions of the upright frame
+    pub fn dimensions(&self, raw_dimensions: (usize, usize)) -> (usize, usize) {
+        self.orientation.dimensions(raw_dimensions)
+    }
+
+    // The raw pixel an upright pixel is taken from. When whole frames are
+    // undistorted that is wherever the lens bent it to
+    pub fn raw_pixel(
+        &self,
+        coordinate: (usize, usize),
+        raw_dimensions: (usize, usize),
+    ) -> (usize, usize) {
+        let raw = self.orientation.raw(coordinate, raw_dimensions);
+
+        match self.lens {
+            Some(lens) if lens.undistortion() == Undistortion::Frame => {
+                // Through the middle of the pixel. The corners of the
+                // undistorted frame can fall outside the sensor, those are
+                // filled in from its edge
+                let point = (raw.0 as f64 + 0.5, raw.1 as f64 + 0.5);
+                let (x, y) = lens.distort(point, raw_dimensions);
+                (
+                    x.clamp(0.0, (raw_dimensions.0 - 1) as f64) as usize,
+                    y.clamp(0.0, (raw_dimensions.1 - 1) as f64) as usize,
+                )
+            }
+            _ => raw,
+        }
+    }
+
+    // Where the middle of an upright pixel would be without the lens
+    // distortion, for when only coordinates are undistorted
+    fn undistorted_point(
+        &self,
+        coordinate: (usize, usize),
+        raw_dimensions: (usize, usize),
+    ) -> (f32, f32) {
+        let raw = self.orientation.raw(coordinate, raw_dimensions);
+        let point = (raw.0 as f64 + 0.5, raw.1 as f64 + 0.5);
+
+        let point = match self.lens {
+            Some(lens) => lens.undistort(point, raw_dimensions),
+            None => point,
+        };
+
+        self.orientation
+            .upright_point((point.0 as f32, point.1 as f32), raw_dimensions)
+    }
+}
+
+// Lookup maps for a geometry, precomputed so the lens costs nothing per frame.
+// They are rebuilt whenever the frame size or mask scale changes
+pub struct FrameSampler {
+    geometry: FrameGeometry,
+    // Raw dimensions and mask scale the maps were built for
+    built_for: Option<((usize, usize), usize)>,
+    dimensions: (usize, usize),
+    mask_dimensions: (usize, usize),
+    scale: usize,
+    // Index into the raw image of the pixel each mask pixel is sampled from
+    sources: Vec<usize>,
+    // The same for each luma image pixel
+    luma_sources: Vec<usize>,
+    luma_dimensions: (usize, usize),
+    // Undistorted upright position of each mask pixel, empty unless only
+    // coordinates are undistorted
+    positions: Vec<(f32, f32)>,
+}
+
+impl FrameSampler {
+    pub fn new(geometry: FrameGeometry) -> Self {
+        Self {
+            geometry,
+            built_for: None,
+            dimensions: (0, 0),
+            mask_dimensions: (0, 0),
+            scale: 1,
+            sources: Vec::new(),
+            luma_sources: Vec::new(),
+            luma_dimensions: (0, 0),
+            positions: Vec::new(),
+        }
+    }
+
+    pub fn geometry(&self) -> FrameGeometry {
+        self.geometry
+    }
+
+    // Builds the maps for frames of these dimensions, unless they already are
+    pub fn prepare(&mut self, raw_dimensions: (usize, usize), scale: usize) {
+        if self.built_for == Some((raw_dimensions, scale)) {
+            return;
+        }
+
+        let dimensions = self.geometry.dimensions(raw_dimensions);
+        let mask_dimensions = (dimensions.0 / scale, dimensions.1 / scale);
+        let luma_dimensions = (dimensions.0 / LUMA_SCALE, dimensions.1 / LUMA_SCALE);
+
+        self.sources = self.build_sources(raw_dimensions, mask_dimensions, scale);
+        self.luma_sources = self.build_sources(raw_dimensions, luma_dimensions, LUMA_SCALE);
+
+        let coordinates = self
+            .geometry
+            .lens
+            .is_some_and(|l| l.undistortion() == Undistortion::Coordinates);
+        self.positions = if coordinates {
+            Self::samples(mask_dimensions, scale)
+                .map(|upright| self.geometry.undistorted_point(upright, raw_dimensions))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        self.built_for = Some((raw_dimensions, scale));
+        self.dimensions = dimensions;
+        self.mask_dimensions = mask_dimensions;
+        self.luma_dimensions = luma_dimensions;
+        self.scale = scale;
+    }
+
+    // The middle pixel of each scale x scale block of the upright frame, row
+    // by row
+    fn samples(
+        dimensions: (usize, usize),
+        scale: usize,
+    ) -> impl Iterator<Item = (usize, usize)> + Clone {
+        (0..dimensions.1).flat_map(move |y| {
+            (0..dimensions.0).map(move |x| (x * scale + scale / 2, y * scale + scale / 2))
+        })
+    }
+
+    fn build_sources(
+        &self,
+        raw_dimensions: (usize, usize),
+        dimensions: (usize, usize),
+        scale: usize,
+    ) -> Vec<usize> {
+        Self::samples(dimensions, scale)
+            .map(|upright| {
+                let (x, y) = self.geometry.raw_pixel(upright, raw_dimensions);
+                y * raw_dimensions.0 + x
+            })
+            .collect()
+    }
+
+    // One raw pixel index per mask pixel, in upright order
+    pub fn sources(&self) -> &[usize] {
+        &self.sources
+    }
+
+    // One raw pixel index per luma image pixel, and the luma image's
+    // dimensions
+    pub fn luma_sources(&self) -> (&[usize], (usize, usize)) {
+        (&self.luma_sources, self.luma_dimensions)
+    }
+
+    // Where a continuous upright point ends up once undistorted. Unchanged
+    // unless only coordinates are undistorted, since otherwise the frame
+    // already is
+    pub fn correct_point(&self, point: (f32, f32)) -> (f32, f32) {
+        if self.positions.is_empty() {
+            return point;
+        }
+
+        // Move the nearest mask pixel's sample, and keep the point's offset
+        // from it
+        let cell = (
+            ((point.0.max(0.0) as usize) / self.scale).min(self.mask_dimensions.0 - 1),
+            ((point.1.max(0.0) as usize) / self.scale).min(self.mask_dimensions.1 - 1),
+        );
+        let (x, y) = self.positions[cell.1 * self.mask_dimensions.0 + cell.0];
+        let middle = (
+            (cell.0 * self.scale + self.scale / 2) as f32 + 0.5,
+            (cell.1 * self.scale + self.scale / 2) as f32 + 0.5,
+        );
+
+        (x + point.0 - middle.0, y + point.1 - middle.1)
+    }
+
+    // correct_point for a pixel, kept inside the frame
+    pub fn correct(&self, coordinate: (usize, usize)) -> (usize, usize) {
+        if self.positions.is_empty() {
+            return coordinate;
+        }
+
+        let (x, y) = self.correct_point((coordinate.0 as f32 + 0.5, coordinate.1 as f32 + 0.5));
+
+        // The position is continuous, back to the pixel it falls in
+        (
+            (x - 0.5).round().clamp(0.0, (self.dimensions.0 - 1) as f32) as usize,
+            (y - 0.5).round().clamp(0.0, (self.dimensions.1 - 1) as f32) as usize,
+        )
+    }
+}
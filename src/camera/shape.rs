@@ -0,0 +1,392 @@
+use std::f32::consts::PI;
+use std::fmt;
+
+// Neighbours clockwise from the right, in upright coordinates where y grows
+// downwards
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+// Outlines with fewer points than this are too small to tell shapes apart
+const MIN_CONTOUR: usize = 12;
+
+// Furthest the outline can stray from its polygon, as a fraction of the
+// perimeter. Large enough to cut the corners the mask filters round off, small
+// enough that a circle still needs more than four sides
+const POLYGON_TOLERANCE: f32 = 0.025;
+
+// Steps in the traced outline up to this many pixels deep are smoothed out
+// before it is measured, they come from the pixel grid rather than the shape
+const STEP_TOLERANCE: f32 = 0.75;
+
+// Circularity a circle reaches at least, a square's is about 0.79 and a
+// triangle's 0.6
+const MIN_CIRCULARITY: f32 = 0.8;
+
+// Nearest over furthest point of the outline from its middle that a circle
+// reaches at least. A square's is 0.71, small ones read a little higher, and
+// they can look as round as a small circle otherwise
+const MIN_ROUNDNESS: f32 = 0.77;
+
+// Longest over shortest side of a quadrilateral that is still a square
+const MAX_SQUARE_RATIO: f32 = 1.4;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum Shape {
+    Circle,
+    Triangle,
+    Square,
+    Rectangle,
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Shape::Circle => write!(f, "circle"),
+            Shape::Triangle => write!(f, "triangle"),
+            Shape::Square => write!(f, "square"),
+            Shape::Rectangle => write!(f, "rectangle"),
+            Shape::Unknown => write!(f, "blob"),
+        }
+    }
+}
+
+// What a blob's outline looks like
+#[derive(Clone, Copy, Default)]
+pub struct Outline {
+    pub shape: Shape,
+    // 4 pi area / perimeter squared, 1 for a perfect circle and lower for
+    // anything else
+    pub circularity: f32,
+    // Corners of the polygon the outline simplifies to
+    pub vertices: usize,
+}
+
+// The outer boundary of the 8-connected region of `class` that `start` is the
+// first pixel of in scan order, as mask pixels clockwise from `start`
+fn trace_contour(
+    mask: &[u8],
+    dimensions: (usize, usize),
+    class: u8,
+    start: (usize, usize),
+) -> Vec<(usize, usize)> {
+    let (width, height) = (dimensions.0 as isize, dimensions.1 as isize);
+    let inside = |(x, y): (isize, isize)| {
+        x >= 0 && y >= 0 && x < width && y < height && mask[(y * width + x) as usize] == class
+    };
+
+    // Moore neighbour tracing: keep a pixel outside the region next to the
+    // current one, and turn clockwise from it to the next pixel inside. The
+    // pixel left of the first one in scan order is always outside
+    let start = (start.0 as isize, start.1 as isize);
+    let (mut current, mut back) = (start, (start.0 - 1, start.1));
+
+    // The walk is done when it leaves the start the same way it first did
+    let mut second = None;
+
+    let mut contour = vec![(start.0 as usize, start.1 as usize)];
+    // Every boundary pixel is visited at most 4 times
+    let limit = 4 * mask.len() + 8;
+    for _ in 0..limit {
+        let offset = (back.0 - current.0, back.1 - current.1);
+        let from = NEIGHBOURS.iter().position(|&n| n == offset).unwrap_or(4);
+
+        let mut next = None;
+        for turn in 1..=8 {
+            let direction = NEIGHBOURS[(from + turn) % 8];
+            let neighbour = (current.0 + direction.0, current.1 + direction.1);
+            if inside(neighbour) {
+                next = Some(neighbour);
+                break;
+            }
+            back = neighbour;
+        }
+
+        // A single pixel
+        let Some(next) = next else {
+            break;
+        };
+
+        if current == start {
+            if second == Some(next) {
+                break;
+            }
+            second.get_or_insert(next);
+        }
+
+        current = next;
+        contour.push((current.0 as usize, current.1 as usize));
+    }
+
+    // The walk ends where it began
+    if contour.len() > 1 && contour.last() == contour.first() {
+        contour.pop();
+    }
+
+    contour
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+// Distance of a point from the line through two others
+fn line_distance(point: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let length = distance(a, b);
+    if length < f32::EPSILON {
+        return distance(point, a);
+    }
+
+    ((b.0 - a.0) * (a.1 - point.1) - (a.0 - point.0) * (b.1 - a.1)).abs() / length
+}
+
+// Douglas-Peucker: the indices of the points between `first` and `last` that
+// have to be kept for the polygon to stay within `tolerance` of them
+fn simplify(
+    points: &[(f32, f32)],
+    first: usize,
+    last: usize,
+    tolerance: f32,
+    kept: &mut Vec<usize>,
+) {
+    let farthest = (first + 1..last)
+        .map(|i| (i, line_distance(points[i], points[first], points[last])))
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+
+    if let Some((index, distance)) = farthest
+        && distance > tolerance
+    {
+        simplify(points, first, index, tolerance, kept);
+        kept.push(index);
+        simplify(points, index, last, tolerance, kept);
+    }
+}
+
+// Corners of a closed outline simplified to within `tolerance`
+fn polygon(points: &[(f32, f32)], tolerance: f32) -> Vec<(f32, f32)> {
+    // A closed outline has no ends to start from, so split it at the point
+    // furthest from the first and simplify both halves
+    let Some(opposite) = (1..points.len()).max_by(|&a, &b| {
+        distance(points[a], points[0]).total_cmp(&distance(points[b], points[0]))
+    }) else {
+        return points.to_vec();
+    };
+
+    let mut closed = points.to_vec();
+    closed.push(points[0]);
+
+    let mut kept = vec![0];
+    simplify(&closed, 0, opposite, tolerance, &mut kept);
+    kept.push(opposite);
+    simplify(&closed, opposite, closed.len() - 1, tolerance, &mut kept);
+
+    // The first point was only kept to split at, drop it if it lies on a
+    // straight side
+    let mut corners: Vec<(f32, f32)> = kept.iter().map(|&i| points[i]).collect();
+    if corners.len() > 3 {
+        let (previous, next) = (corners[corners.len() - 1], corners[1]);
+        if line_distance(corners[0], previous, next) <= tolerance {
+            corners.remove(0);
+        }
+    }
+
+    corners
+}
+
+// Area enclosed by a polygon, by the shoelace formula
+fn area(points: &[(f32, f32)]) -> f32 {
+    let twice: f32 = points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum();
+    twice.abs() / 2.0
+}
+
+fn perimeter(points: &[(f32, f32)]) -> f32 {
+    points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(&a, &b)| distance(a, b))
+        .sum()
+}
+
+// Nearest over furthest distance of the outline's points from their mean,
+// out to the region's edge
+fn roundness(points: &[(f32, f32)]) -> f32 {
+    let count = points.len() as f32;
+    let middle = points
+        .iter()
+        .fold((0.0, 0.0), |sum, p| (sum.0 + p.0 / count, sum.1 + p.1 / count));
+
+    let radii = points.iter().map(|&p| distance(p, middle) + 0.5);
+    let nearest = radii.clone().fold(f32::INFINITY, f32::min);
+    let furthest = radii.fold(0.0, f32::max);
+    nearest / furthest
+}
+
+// Traces the outline of the region `start` is the first pixel of and works
+// out which shape it is
+pub fn describe(
+    mask: &[u8],
+    dimensions: (usize, usize),
+    class: u8,
+    start: (usize, usize),
+) -> Outline {
+    let contour: Vec<(f32, f32)> = trace_contour(mask, dimensions, class, start)
+        .into_iter()
+        .map(|(x, y)| (x as f32, y as f32))
+        .collect();
+    if contour.len() < MIN_CONTOUR {
+        return Outline::default();
+    }
+
+    // The outline runs through the middle of the edge pixels and zigzags
+    // along every slope, which makes small shapes look far less round than
+    // they are. Smooth out the steps, then grow the outline by the half pixel
+    // out to the region's edge
+    let smooth = polygon(&contour, STEP_TOLERANCE);
+    let (smooth_area, smooth_length) = (area(&smooth), perimeter(&smooth));
+    let grown_area = smooth_area + smooth_length / 2.0 + PI / 4.0;
+    let grown_length = smooth_length + PI;
+    let circularity = (4.0 * PI * grown_area / (grown_length * grown_length)).min(1.0);
+
+    let length = perimeter(&contour);
+    let corners = polygon(&contour, POLYGON_TOLERANCE * length);
+    let vertices = corners.len();
+
+    let shape = match vertices {
+        3 => Shape::Triangle,
+        4 => {
+            let sides: Vec<f32> = corners
+                .iter()
+                .zip(corners.iter().cycle().skip(1))
+                .map(|(&a, &b)| distance(a, b))
+                .collect();
+            let longest = sides.iter().copied().fold(0.0, f32::max);
+            let shortest = sides.iter().copied().fold(f32::INFINITY, f32::min);
+
+            if longest <= shortest * MAX_SQUARE_RATIO {
+                Shape::Square
+            } else {
+                Shape::Rectangle
+            }
+        }
+        _ if vertices > 4
+            && circularity >= MIN_CIRCULARITY
+            && roundness(&contour) >= MIN_ROUNDNESS =>
+        {
+            Shape::Circle
+        }
+        _ => Shape::Unknown,
+    };
+
+    Outline {
+        shape,
+        circularity,
+        vertices,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 64;
+
+    // A mask of class 0 wherever the middle of a pixel is inside the shape
+    fn render(inside: impl Fn(f32, f32) -> bool) -> Vec<u8> {
+        (0..SIZE * SIZE)
+            .map(|i| ((i % SIZE) as f32 + 0.5, (i / SIZE) as f32 + 0.5))
+            .map(|(x, y)| if inside(x, y) { 0 } else { 1 })
+            .collect()
+    }
+
+    fn outline(mask: &[u8]) -> Outline {
+        let first = mask.iter().position(|&c| c == 0).unwrap();
+        describe(mask, (SIZE, SIZE), 0, (first % SIZE, first / SIZE))
+    }
+
+    // A regular polygon around (32, 32) with corners `radius` from the middle
+    fn regular(sides: usize, radius: f32, turn: f32) -> impl Fn(f32, f32) -> bool {
+        move |x, y| {
+            (0..sides).all(|i| {
+                let angle = turn + (i as f32 + 0.5) * 2.0 * PI / sides as f32;
+                let reach = radius * (PI / sides as f32).cos();
+                (x - 32.0) * angle.cos() + (y - 32.0) * angle.sin() <= reach
+            })
+        }
+    }
+
+    #[test]
+    fn small_discs_are_circles() {
+        for radius in [3.0, 4.0, 4.5, 5.0, 6.0, 8.0, 12.0, 20.0] {
+            for offset in [0.0, 0.25, 0.5] {
+                let (cx, cy) = (32.0 + offset, 32.0 + offset);
+                let mask = render(|x, y| (x - cx).hypot(y - cy) <= radius);
+                let outline = outline(&mask);
+                assert!(
+                    outline.shape == Shape::Circle,
+                    "disc of radius {} at offset {} is a {} with circularity {:.2}",
+                    radius,
+                    offset,
+                    outline.shape,
+                    outline.circularity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn squares_are_squares() {
+        for radius in [10.0, 15.0, 25.0] {
+            for turn in [0.0, 0.15, 0.35] {
+                let outline = outline(&render(regular(4, radius, turn)));
+                assert!(
+                    outline.shape == Shape::Square,
+                    "square of radius {} turned {} is a {}",
+                    radius,
+                    turn,
+                    outline.shape
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rectangles_are_rectangles() {
+        let outline = outline(&render(|x, y| (x - 32.0).abs() <= 20.0 && (y - 32.0).abs() <= 8.0));
+        assert!(outline.shape == Shape::Rectangle);
+    }
+
+    #[test]
+    fn triangles_are_triangles() {
+        for radius in [8.0, 15.0, 25.0] {
+            for turn in [0.0, 0.3, 0.5] {
+                let outline = outline(&render(regular(3, radius, turn)));
+                assert!(
+                    outline.shape == Shape::Triangle,
+                    "triangle of radius {} turned {} is a {}",
+                    radius,
+                    turn,
+                    outline.shape
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_regions_are_unknown() {
+        let outline = outline(&render(|x, y| (x - 32.0).abs() < 1.0 && (y - 32.0).abs() < 1.0));
+        assert!(outline.shape == Shape::Unknown);
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Where calibration and profile files live, relative to the working directory
+// unless overridden
+const DEFAULT_CONFIG_DIR: &str = "config";
+
+pub fn config_dir() -> PathBuf {
+    match std::env::var_os("ROBOT_CONFIG_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(DEFAULT_CONFIG_DIR),
+    }
+}
+
+pub fn config_path(name: &str) -> PathBuf {
+    config_dir().join(name)
+}
+
+// A minimal INI style file:
+//
+// # Comment
+// key = value
+//
+// [section]
+// key = value
+//
+// Keys before the first header belong to the unnamed section ""
+#[derive(Default)]
+pub struct Config {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    // Loads a settings file from the config directory and parses it. A
+    // missing file quietly gives the defaults, any other failure is reported
+    // first. `what` names the settings in that report
+    pub fn load_or_default<T: Default>(
+        file: &str,
+        what: &str,
+        parse: impl FnOnce(&Config) -> std::io::Result<T>,
+    ) -> T {
+        match Self::load(&config_path(file)).and_then(|config| parse(&config)) {
+            Ok(settings) => settings,
+            Err(e) if e.kind() == ErrorKind::NotFound => T::default(),
+            Err(e) => {
+                println!("Failed to load the {}, using defaults: {}", what, e);
+                T::default()
+            }
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Config::new();
+        let mut section = String::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let Some(name) = header.strip_suffix(']') else {
+                    return Err(format!("line {}: unterminated section header", number + 1));
+                };
+
+                section = name.trim().to_string();
+                config.section_mut(&section);
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `key = value`", number + 1));
+            };
+
+            config.set(&section, key.trim(), value.trim());
+        }
+
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut text = String::new();
+        for (name, entries) in &self.sections {
+            if !name.is_empty() {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+
+                text.push_str(&format!("[{}]\n", name));
+            }
+
+            for (key, value) in entries {
+                text.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
+
+        fs::write(path, text)
+    }
+
+    fn section_mut(&mut self, section: &str) -> &mut Vec<(String, String)> {
+        let index = match self.sections.iter().position(|(name, _)| name == section) {
+            Some(i) => i,
+            None => {
+                self.sections.push((section.to_string(), Vec::new()));
+                self.sections.len() - 1
+            }
+        };
+
+        &mut self.sections[index].1
+    }
+
+    pub fn set<T: Display>(&mut self, section: &str, key: &str, value: T) {
+        let entries = self.section_mut(section);
+        let value = value.to_string();
+
+        match entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key.to_string(), value)),
+        }
+    }
+
+    // Every named section, in file order
+    pub fn section_names(&self) -> impl Iterator<Item = &str> {
+        self.sections
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| !name.is_empty())
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        let (_, entries) = self.sections.iter().find(|(name, _)| name == section)?;
+        let (_, value) = entries.iter().find(|(k, _)| k == key)?;
+
+        Some(value)
+    }
+
+    // Reads and parses a value, failing if it is missing or malformed
+    pub fn parse_value<T: FromStr>(&self, section: &str, key: &str) -> std::io::Result<T> {
+        let value = self.get(section, key).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("missing `{}` in section [{}]", key, section),
+            )
+        })?;
+
+        value.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid value `{}` for `{}` in section [{}]", value, key, section),
+            )
+        })
+    }
+
+    // Like parse_value, but a missing value falls back to the default
+    pub fn parse_or<T: FromStr>(&self, section: &str, key: &str, default: T) -> std::io::Result<T> {
+        if self.get(section, key).is_none() {
+            return Ok(default);
+        }
+
+        self.parse_value(section, key)
+    }
+
+    pub fn entries(&self, section: &str) -> impl Iterator<Item = (&str, &str)> {
+        self.sections
+            .iter()
+            .filter(move |(name, _)| name == section)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
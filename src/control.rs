@@ -29,6 +29,13 @@ enum Register {
 
 pub struct Robot {
     _internal_device: LinuxI2CDevice,
+
+    // Last angles sent to the camera servos, None until the first move
+    camera_pan: Option<u8>,
+    camera_tilt: Option<u8>,
+
+    // The last motion the wheels were asked to make
+    last_drive: Option<movement::Drive>,
 }
 
 impl Robot {
@@ -37,6 +44,11 @@ impl Robot {
 
         Ok(Robot {
             _internal_device: device,
+
+            camera_pan: None,
+            camera_tilt: None,
+
+            last_drive: None,
         })
     }
 
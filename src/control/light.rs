@@ -37,6 +37,14 @@ impl LightColor {
         Self::new(0, 0, 255)
     }
 
+    pub fn orange() -> Self {
+        Self::new(255, 64, 0)
+    }
+
+    pub fn purple() -> Self {
+        Self::new(160, 0, 255)
+    }
+
     pub fn black() -> Self {
         Self::new(0, 0, 0)
     }
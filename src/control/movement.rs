@@ -1,4 +1,8 @@
-use std::{fmt, thread::sleep, time::Duration};
+use std::{
+    fmt,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use i2cdev::linux::LinuxI2CError;
 
@@ -54,6 +58,26 @@ impl fmt::Display for Rotation {
     }
 }
 
+// A motion the wheels were asked to make
+#[derive(Clone, Copy)]
+pub struct Drive {
+    // Each from -1 to 1, positive forward, right and clockwise
+    pub forward: f32,
+    pub strafe: f32,
+    pub turn: f32,
+    pub speed: u8,
+    pub started: Instant,
+    // None while the wheels are still turning
+    pub stopped: Option<Instant>,
+}
+
+impl Drive {
+    // How long the wheels turned for, None while they still are
+    pub fn duration(&self) -> Option<Duration> {
+        Some(self.stopped?.saturating_duration_since(self.started))
+    }
+}
+
 impl Robot {
     pub(super) fn move_motor(
         &mut self,
@@ -69,7 +93,22 @@ impl Robot {
         Ok(())
     }
 
+    // Stops the wheels, and records the last drive as over even when a write
+    // fails, so a failed stop doesn't leave the robot looking like it is
+    // still moving
     pub fn stop(&mut self) -> ControlError<LinuxI2CError> {
+        let stopped = self.stop_motors();
+
+        if let Some(drive) = &mut self.last_drive
+            && drive.stopped.is_none()
+        {
+            drive.stopped = Some(Instant::now());
+        }
+
+        stopped
+    }
+
+    fn stop_motors(&mut self) -> ControlError<LinuxI2CError> {
         self.write_block_data(
             Register::MotorControl,
             &[Motor::ForwardLeft as u8, MotorDirection::Forward as u8, 0u8],
@@ -105,33 +144,77 @@ impl Robot {
         Ok(())
     }
 
-    pub fn move_rotate(
+    // Sets the wheels, keeps them turning for `duration` and stops them. They
+    // are stopped as well when setting them fails part way
+    fn drive_wheels(
         &mut self,
-        direction: Rotation,
-        speed: u8,
+        wheels: [(Motor, MotorDirection, u8); 4],
         duration: Duration,
     ) -> ControlError<LinuxI2CError> {
-        match direction {
-            Rotation::CounterClockwise => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Forward, speed)?;
-            }
-
-            Rotation::Clockwise => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Reverse, speed)?;
-            }
+        let set = wheels
+            .into_iter()
+            .try_for_each(|(motor, direction, speed)| self.move_motor(motor, direction, speed));
+        if set.is_err() {
+            _ = self.stop();
+            return set;
         }
 
         sleep(duration);
 
-        self.stop()?;
+        self.stop()
+    }
 
-        Ok(())
+    // Records a motion the wheels are being set to
+    fn start_drive(&mut self, forward: f32, strafe: f32, turn: f32, speed: u8) {
+        self.last_drive = Some(Drive {
+            forward,
+            strafe,
+            turn,
+            speed,
+            started: Instant::now(),
+            stopped: None,
+        });
+    }
+
+    pub fn last_drive(&self) -> Option<Drive> {
+        self.last_drive
+    }
+
+    // When the wheels were last turning, which is now while they still are.
+    // None if they haven't turned yet
+    pub fn last_driven(&self) -> Option<Instant> {
+        self.last_drive.map(|drive| drive.stopped.unwrap_or_else(Instant::now))
+    }
+
+    pub fn move_rotate(
+        &mut self,
+        direction: Rotation,
+        speed: u8,
+        duration: Duration,
+    ) -> ControlError<LinuxI2CError> {
+        let turn = match direction {
+            Rotation::Clockwise => 1.0,
+            Rotation::CounterClockwise => -1.0,
+        };
+        self.start_drive(0.0, 0.0, turn, speed);
+
+        let wheels = match direction {
+            Rotation::CounterClockwise => [
+                (Motor::ForwardLeft, MotorDirection::Reverse, speed),
+                (Motor::ForwardRight, MotorDirection::Forward, speed),
+                (Motor::BackwardLeft, MotorDirection::Reverse, speed),
+                (Motor::BackwardRight, MotorDirection::Forward, speed),
+            ],
+
+            Rotation::Clockwise => [
+                (Motor::ForwardLeft, MotorDirection::Forward, speed),
+                (Motor::ForwardRight, MotorDirection::Reverse, speed),
+                (Motor::BackwardLeft, MotorDirection::Forward, speed),
+                (Motor::BackwardRight, MotorDirection::Reverse, speed),
+            ],
+        };
+
+        self.drive_wheels(wheels, duration)
     }
 
     pub fn move_direction(
@@ -140,41 +223,79 @@ impl Robot {
         speed: u8,
         duration: Duration,
     ) -> ControlError<LinuxI2CError> {
-        match direction {
-            Direction::Forward => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Forward, speed)?;
-            }
-
-            Direction::Backward => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Reverse, speed)?;
-            }
-
-            Direction::Left => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Reverse, speed)?;
-            }
-
-            Direction::Right => {
-                self.move_motor(Motor::ForwardLeft, MotorDirection::Forward, speed)?;
-                self.move_motor(Motor::ForwardRight, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardLeft, MotorDirection::Reverse, speed)?;
-                self.move_motor(Motor::BackwardRight, MotorDirection::Forward, speed)?;
-            }
-        }
+        let (forward, strafe) = match direction {
+            Direction::Forward => (1.0, 0.0),
+            Direction::Backward => (-1.0, 0.0),
+            Direction::Left => (0.0, -1.0),
+            Direction::Right => (0.0, 1.0),
+        };
+        self.start_drive(forward, strafe, 0.0, speed);
+
+        let wheels = match direction {
+            Direction::Forward => [
+                (Motor::ForwardLeft, MotorDirection::Forward, speed),
+                (Motor::ForwardRight, MotorDirection::Forward, speed),
+                (Motor::BackwardLeft, MotorDirection::Forward, speed),
+                (Motor::BackwardRight, MotorDirection::Forward, speed),
+            ],
 
-        sleep(duration);
+            Direction::Backward => [
+                (Motor::ForwardLeft, MotorDirection::Reverse, speed),
+                (Motor::ForwardRight, MotorDirection::Reverse, speed),
+                (Motor::BackwardLeft, MotorDirection::Reverse, speed),
+                (Motor::BackwardRight, MotorDirection::Reverse, speed),
+            ],
 
-        self.stop()?;
+            Direction::Left => [
+                (Motor::ForwardLeft, MotorDirection::Reverse, speed),
+                (Motor::ForwardRight, MotorDirection::Forward, speed),
+                (Motor::BackwardLeft, MotorDirection::Forward, speed),
+                (Motor::BackwardRight, MotorDirection::Reverse, speed),
+            ],
 
-        Ok(())
+            Direction::Right => [
+                (Motor::ForwardLeft, MotorDirection::Forward, speed),
+                (Motor::ForwardRight, MotorDirection::Reverse, speed),
+                (Motor::BackwardLeft, MotorDirection::Reverse, speed),
+                (Motor::BackwardRight, MotorDirection::Forward, speed),
+            ],
+        };
+
+        self.drive_wheels(wheels, duration)
+    }
+
+    // Drives forward, sideways and turns at once, which the mecanum wheels
+    // allow. Each part goes from -1 to 1, positive forward, right and
+    // clockwise, and the wheels are scaled down together when they add up
+    // to more than full speed
+    pub fn move_mecanum(
+        &mut self,
+        forward: f32,
+        strafe: f32,
+        turn: f32,
+        speed: u8,
+        duration: Duration,
+    ) -> ControlError<LinuxI2CError> {
+        let wheels = [
+            (Motor::ForwardLeft, forward + strafe + turn),
+            (Motor::ForwardRight, forward - strafe - turn),
+            (Motor::BackwardLeft, forward - strafe + turn),
+            (Motor::BackwardRight, forward + strafe - turn),
+        ];
+        let largest = wheels.iter().fold(1.0f32, |largest, w| largest.max(w.1.abs()));
+        self.start_drive(forward, strafe, turn, speed);
+
+        let wheels = wheels.map(|(motor, power)| {
+            let direction = if power < 0.0 {
+                MotorDirection::Reverse
+            } else {
+                MotorDirection::Forward
+            };
+            let wheel_speed = (f32::from(speed) * power.abs() / largest).round() as u8;
+            (motor, direction, wheel_speed)
+        });
+
+        self.drive_wheels(wheels, duration)
     }
 
     pub(super) fn test_movement(&mut self) -> ControlError<LinuxI2CError> {
@@ -25,9 +25,22 @@ impl Robot {
 
         self.write_block_data(Register::ServoControl, &[servo as u8, angle])?;
 
+        match servo {
+            Servo::CameraPan => self.camera_pan = Some(angle),
+            Servo::CameraTilt => self.camera_tilt = Some(angle),
+        }
+
         Ok(())
     }
 
+    // The angle the servo was last moved to, after clamping
+    pub fn servo_angle(&self, servo: Servo) -> Option<u8> {
+        match servo {
+            Servo::CameraPan => self.camera_pan,
+            Servo::CameraTilt => self.camera_tilt,
+        }
+    }
+
     pub(super) fn test_servos(&mut self) -> ControlError<LinuxI2CError> {
         for x in 0u8..255 {
             self.move_servo(Servo::CameraPan, x)?;
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+
+use crate::camera::{BlobTarget, Frame};
+use crate::config::Config;
+
+const DECISION_FILE: &str = "decision.conf";
+
+#[derive(Clone, Copy)]
+pub struct DecisionSettings {
+    // Frames that are voted over
+    pub window: usize,
+    // Votes out of the window a class needs before the robot switches to it.
+    // Losing every class counts as a vote for nothing, so the robot also
+    // needs this many empty frames before it gives up on a class
+    pub votes: usize,
+    // Confidence a class needs in a frame to get its vote
+    pub enter_confidence: f32,
+    // Confidence the current class keeps its votes down to, lower than the
+    // enter confidence so a class on the edge doesn't flicker in and out
+    pub exit_confidence: f32,
+    // How far the smoothed coordinate moves towards each new one, from 0 to 1
+    pub smoothing: f32,
+}
+
+impl Default for DecisionSettings {
+    fn default() -> Self {
+        Self {
+            window: 5,
+            votes: 3,
+            enter_confidence: 0.2,
+            exit_confidence: 0.1,
+            smoothing: 0.4,
+        }
+    }
+}
+
+impl DecisionSettings {
+    // decision.conf:
+    //
+    // window = 5
+    // votes = 3
+    // enter_confidence = 0.2
+    // exit_confidence = 0.1
+    // smoothing = 0.4
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            window: config.parse_or("", "window", defaults.window)?,
+            votes: config.parse_or("", "votes", defaults.votes)?,
+            enter_confidence: config.parse_or("", "enter_confidence", defaults.enter_confidence)?,
+            exit_confidence: config.parse_or("", "exit_confidence", defaults.exit_confidence)?,
+            smoothing: config.parse_or("", "smoothing", defaults.smoothing)?,
+        };
+
+        if settings.votes == 0 || settings.votes > settings.window {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "votes must be between 1 and the window size",
+            ));
+        }
+
+        if settings.exit_confidence > settings.enter_confidence {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "exit_confidence must be at most enter_confidence",
+            ));
+        }
+
+        if settings.smoothing <= 0.0 || settings.smoothing > 1.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "smoothing must be above 0 and at most 1",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(DECISION_FILE, "decision settings", Self::parse)
+    }
+}
+
+// What the robot has settled on going after
+#[derive(Clone, Copy)]
+pub struct Decision {
+    pub class: u8,
+    // Smoothed centroid of the class's target blob
+    pub coordinate: (usize, usize),
+}
+
+// Turns per-frame detections into a decision that only changes after it has
+// been seen for several frames, so a single noisy frame can't flip the lights
+// or set off an action
+pub struct DecisionFilter {
+    settings: DecisionSettings,
+    // The most recent vote last, None for a frame with nothing in it
+    votes: VecDeque<Option<u8>>,
+    class: Option<u8>,
+    coordinate: Option<(f32, f32)>,
+}
+
+impl DecisionFilter {
+    pub fn new(settings: DecisionSettings) -> Self {
+        Self {
+            settings,
+            votes: VecDeque::with_capacity(settings.window),
+            class: None,
+            coordinate: None,
+        }
+    }
+
+    pub fn update(&mut self, frame: &Frame, target: BlobTarget) -> Option<Decision> {
+        let vote = match self.class {
+            Some(class) if frame.confidence(class) >= self.settings.exit_confidence => Some(class),
+            _ => frame
+                .detections()
+                .iter()
+                .find(|d| d.confidence >= self.settings.enter_confidence)
+                .map(|d| d.class),
+        };
+
+        if self.votes.len() == self.settings.window {
+            self.votes.pop_front();
+        }
+        self.votes.push_back(vote);
+
+        // Whatever has the most votes wins if it has enough of them, the
+        // current decision wins ties
+        let mut winner = (self.class, self.count(self.class));
+        for &candidate in &self.votes {
+            let count = self.count(candidate);
+            if count > winner.1 {
+                winner = (candidate, count);
+            }
+        }
+
+        if winner.0 != self.class && winner.1 >= self.settings.votes {
+            self.class = winner.0;
+            self.coordinate = None;
+        }
+
+        let class = self.class?;
+
+        // Keep the last coordinate through frames where the blob was missed
+        if let Some(blob) = frame.target_blob(class, target) {
+            let measured = (blob.centroid.0 as f32, blob.centroid.1 as f32);
+            let smoothing = self.settings.smoothing;
+
+            self.coordinate = Some(match self.coordinate {
+                Some(c) => (
+                    c.0 + (measured.0 - c.0) * smoothing,
+                    c.1 + (measured.1 - c.1) * smoothing,
+                ),
+                None => measured,
+            });
+        }
+
+        let (width, height) = frame.dimensions();
+        let coordinate = self.coordinate.unwrap_or((width as f32 / 2.0, height as f32 / 2.0));
+
+        Some(Decision {
+            class,
+            coordinate: (coordinate.0.round() as usize, coordinate.1.round() as usize),
+        })
+    }
+
+    fn count(&self, class: Option<u8>) -> usize {
+        self.votes.iter().filter(|&&v| v == class).count()
+    }
+}
@@ -1,20 +1,202 @@
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use crate::{
-    camera::{CameraVideoStream, ClosestColor},
+    camera::{
+        Blob, BlobTarget, CameraModel, CameraVideoStream, ColorClass, ColorClasses, FloorSettings,
+        Frame, FrameGeometry, GroundPlane, LensModel, LineDetector, LineSettings, MarkerDetector,
+        MarkerSettings, RangeSettings, Shape, calibrate_colors, calibrate_floor, calibrate_lens,
+        calibrate_range, save_marker,
+    },
+    control::servo::Servo,
     control::Robot,
+    decision::{DecisionFilter, DecisionSettings},
+    motion::{MotionDetector, MotionSettings},
+    odometry::{Odometry, OdometrySettings},
+    pipeline::{Pipeline, PipelineError, PipelineSettings},
+    stall::{StallDetector, StallSettings},
+    tracker::{Tracker, TrackerSettings},
 };
 
 use crate::control::light::LightColor;
 
 mod actions;
 mod camera;
+mod config;
 mod control;
+mod decision;
+mod motion;
+mod odometry;
+mod pipeline;
+mod stall;
+mod tracker;
+
+// A red box is only steered around when its whole outline is in view, since
+// a ball cut off by the edge of the frame can look square
+const OBSTACLE_BORDER_MARGIN: usize = 8;
+
+// Narrowest a box has to look, as a fraction of the frame width, to be close
+// enough to be in the way. Small boxes are too few pixels to trust the shape
+const MIN_OBSTACLE_WIDTH: f32 = 0.1;
+
+// Least of its bounding box a box fills, a square turned 45° fills half
+const MIN_OBSTACLE_FILL: f32 = 0.5;
+
+fn is_obstacle(blob: &Blob, dimensions: (usize, usize)) -> bool {
+    matches!(blob.shape(), Shape::Square | Shape::Rectangle)
+        && !blob.touches_border(dimensions, OBSTACLE_BORDER_MARGIN)
+        && blob.width() as f32 >= MIN_OBSTACLE_WIDTH * dimensions.0 as f32
+        && blob.fill_ratio() >= MIN_OBSTACLE_FILL
+}
+
+// The value following a flag, e.g. `--camera-profile bright`
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|a| a != name);
+    args.next()?;
+    args.next()
+}
+
+// Several values following a flag, e.g. `--calibrate-range red 50`
+fn arg_values(name: &str, count: usize) -> Option<Vec<String>> {
+    let values: Vec<String> = std::env::args()
+        .skip_while(|a| a != name)
+        .skip(1)
+        .take(count)
+        .collect();
+    (values.len() == count).then_some(values)
+}
+
+fn class_light(class: &ColorClass) -> LightColor {
+    LightColor::new(class.light.0, class.light.1, class.light.2)
+}
+
+// Where each marker in the frame is, by its label if it has one
+fn print_markers(frame: &Frame, camera_model: &CameraModel, robot: &Robot) {
+    for marker in frame.markers() {
+        let bearing = camera_model.bearing(
+            marker.coordinate(),
+            frame.dimensions(),
+            robot.servo_angle(Servo::CameraPan),
+            robot.servo_angle(Servo::CameraTilt),
+        );
+        let name = marker.label.clone().unwrap_or_else(|| format!("Marker {}", marker.id));
+        println!("{} is at {}", name, bearing);
+    }
+}
+
+// One-off camera commands that run instead of the robot, returns whether
+// any were given
+fn camera_command(robot: &mut Robot) -> std::io::Result<bool> {
+    let list_controls = std::env::args().any(|a| a == "--list-controls");
+    let save_profile = arg_value("--save-camera-profile");
+    let calibrate = std::env::args().any(|a| a == "--calibrate-colors");
+    let calibrate_ground = std::env::args().any(|a| a == "--calibrate-floor");
+    let calibrate_camera_lens = std::env::args().any(|a| a == "--calibrate-lens");
+    let calibrate_target_range = std::env::args().any(|a| a == "--calibrate-range");
+
+    if !list_controls
+        && save_profile.is_none()
+        && !calibrate
+        && !calibrate_ground
+        && !calibrate_camera_lens
+        && !calibrate_target_range
+    {
+        return Ok(false);
+    }
+
+    let mut camera_stream = CameraVideoStream::new()?;
+
+    // Calibrate under the same camera settings the robot will run with
+    if let Some(name) = arg_value("--camera-profile") {
+        camera_stream.load_profile(&name)?;
+    }
+
+    if list_controls {
+        camera_stream.print_controls()?;
+    }
+
+    if let Some(name) = save_profile {
+        camera_stream.save_profile(&name)?;
+    }
+
+    if calibrate {
+        // Show which target to hold up next
+        calibrate_colors(&mut camera_stream, |class| {
+            _ = robot.set_all_lights(class_light(class));
+        })?;
+
+        _ = robot.set_all_lights(LightColor::black());
+    }
+
+    // Before the floor, which is mapped in undistorted coordinates
+    if calibrate_camera_lens {
+        calibrate_lens(&mut camera_stream)?;
+    }
+
+    if calibrate_ground {
+        let settings = FloorSettings::load()?;
+
+        // The floor is only mapped for these servo angles, anything else is
+        // corrected for later
+        robot
+            .move_servo(Servo::CameraPan, settings.pan)
+            .map_err(std::io::Error::other)?;
+        robot
+            .move_servo(Servo::CameraTilt, settings.tilt)
+            .map_err(std::io::Error::other)?;
+
+        calibrate_floor(&mut camera_stream, &settings, &CameraModel::load())?;
+    }
+
+    if calibrate_target_range {
+        // The class of the target and its distance from the camera in
+        // centimetres
+        let values = arg_values("--calibrate-range", 2);
+        let Some((class, Ok(distance))) = values.as_ref().map(|v| (&v[0], v[1].parse())) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "expected --calibrate-range <class> <distance in cm>",
+            ));
+        };
+
+        calibrate_range(&mut camera_stream, &CameraModel::load(), class, distance)?;
+    }
+
+    Ok(true)
+}
 
 fn main() {
     let test = std::env::args().any(|a| a == "--test");
     let debug = std::env::args().any(|a| a == "--debug");
 
+    // Printing a marker doesn't need the robot or the camera
+    if let Some(id) = arg_value("--print-marker") {
+        match id.parse() {
+            Ok(id) => {
+                if let Err(e) = save_marker(id) {
+                    println!("Failed to save the marker: {}", e);
+                }
+            }
+            Err(_) => println!("Unknown marker id `{}`", id),
+        }
+        return;
+    }
+
+    // Follow one object by its track instead of the class's best blob in
+    // each frame
+    let follow_track = std::env::args().any(|a| a == "--track");
+
+    // Drive up to targets of a known size and stop at the configured range,
+    // instead of running the class's action
+    let approach = std::env::args().any(|a| a == "--approach");
+
+    // Follow a line on the floor instead of looking for targets
+    let follow_line = std::env::args().any(|a| a == "--follow-line");
+
+    // Sit still and turn towards anything that moves instead of looking for
+    // targets
+    let sentry = std::env::args().any(|a| a == "--sentry");
+
     let mut robot = match Robot::new() {
         Ok(r) => r,
         Err(e) => {
@@ -23,70 +205,344 @@ fn main() {
         }
     };
 
+    match camera_command(&mut robot) {
+        Ok(false) => (),
+        Ok(true) => return,
+        Err(e) => {
+            println!("Camera command failed: {}", e);
+            return;
+        }
+    }
+
     if test {
         _ = robot.test();
         std::process::exit(0)
     }
 
-    let mut camera_stream = match CameraVideoStream::new() {
-        Ok(s) => s,
+    let target = match arg_value("--target") {
+        None => BlobTarget::Largest,
+        Some(t) => match t.parse() {
+            Ok(t) => t,
+            Err(_) => {
+                println!("Unknown target `{}`, expected largest or nearest", t);
+                return;
+            }
+        },
+    };
+
+    let camera_model = CameraModel::load();
+    let marker_settings = MarkerSettings::load();
+
+    // Drive up to one marker, by its name in markers.conf or its id, instead
+    // of looking for targets
+    let approach_marker = match arg_value("--approach-marker") {
+        None => None,
+        Some(name) => match marker_settings.id(&name) {
+            Some(id) => Some(id),
+            None => {
+                println!("Unknown marker `{}`, expected a name from markers.conf or an id", name);
+                return;
+            }
+        },
+    };
+    let find_markers = approach_marker.is_some() || std::env::args().any(|a| a == "--markers");
+    // The line's color class is checked against the same classes the frames
+    // are sorted into
+    let classes = Arc::new(ColorClasses::load());
+    let line_detector =
+        follow_line.then(|| Arc::new(LineDetector::new(LineSettings::load(), &classes)));
+
+    let settings = PipelineSettings {
+        camera_profile: arg_value("--camera-profile"),
+        auto_exposure: std::env::args().any(|a| a == "--auto-exposure"),
+        white_balance: std::env::args().any(|a| a == "--white-balance"),
+        geometry: FrameGeometry::new(camera_model.orientation(), LensModel::load()),
+        classes,
+        markers: find_markers
+            .then(|| Arc::new(MarkerDetector::new(marker_settings, &camera_model))),
+        line: line_detector.clone(),
+    };
+
+    let mut pipeline = match Pipeline::start(settings) {
+        Ok(p) => p,
         Err(e) => {
             println!("Failed to get the camera stream: {}", e);
             return;
         }
     };
 
+    let mut decisions = DecisionFilter::new(DecisionSettings::load());
+    let mut tracker = Tracker::new(TrackerSettings::load());
+    let mut motion = MotionDetector::new(MotionSettings::load());
+    let mut odometry = Odometry::new(OdometrySettings::load());
+    let stall_settings = StallSettings::load();
+    let mut stall = StallDetector::new(stall_settings);
+    let ground_plane = GroundPlane::load();
+    let range_settings = RangeSettings::load();
+
     // Actions
 
     robot.startup_action();
 
     let start_time = SystemTime::now();
     let mut last_action_time = SystemTime::UNIX_EPOCH;
+    let mut last_metrics_time = SystemTime::now();
     loop {
         robot.timer_check(start_time);
-        let frame = camera_stream.get_next_frame();
+        let (frame, captured) = match pipeline.next_frame() {
+            Ok(p) => (p.frame, p.captured),
+
+            // Don't keep driving blind while the camera is being reopened
+            Err(PipelineError::CameraLost) => {
+                _ = robot.stop();
+                _ = robot.set_all_lights(LightColor::orange());
+                continue;
+            }
+
+            Err(e) => {
+                println!("{}, exiting", e);
+                _ = robot.stop();
+                _ = robot.set_all_lights(LightColor::black());
+                return;
+            }
+        };
 
-        let closest_color = frame.closest_color();
+        if debug && last_metrics_time.elapsed().unwrap() > Duration::from_secs(5) {
+            last_metrics_time = SystemTime::now();
+            println!("{}", pipeline.metrics());
+        }
 
         let time_since_last_action = last_action_time.elapsed().unwrap();
 
-        match closest_color {
-            ClosestColor::Red => {
+        odometry.update(
+            &frame,
+            captured,
+            robot.last_drive(),
+            &camera_model,
+            (
+                robot.servo_angle(Servo::CameraPan),
+                robot.servo_angle(Servo::CameraTilt),
+            ),
+        );
+
+        // Whatever the robot is doing, pushing against something it can't
+        // see won't get it anywhere
+        if stall.update(&frame, captured, robot.last_drive())
+            && let Some(drive) = robot.last_drive()
+        {
+            robot.stall_action(drive, &stall_settings);
+        }
+
+        // Keep the error light showing for a while before carrying on
+        if stall.recovering() {
+            if debug {
+                stall.print();
+            }
+            continue;
+        }
+
+        if let Some(line_detector) = &line_detector {
+            if debug {
                 frame.print();
+            }
 
-                _ = robot.set_all_lights(LightColor::red());
+            match frame.line() {
+                Some(line) => {
+                    if time_since_last_action > Duration::from_millis(50) {
+                        last_action_time = SystemTime::now();
+                        _ = robot.set_all_lights(LightColor::green());
+                        robot.follow_line_action(line, line_detector.settings())
+                    }
+                }
 
-                if time_since_last_action > Duration::from_millis(2000) {
-                    last_action_time = SystemTime::now();
-                    robot.red_action()
+                // Lost the line, wait for it to come back into view
+                None => {
+                    _ = robot.stop();
+                    _ = robot.set_all_lights(LightColor::orange());
                 }
             }
 
-            ClosestColor::Green => {
+            continue;
+        }
+
+        if let Some(id) = approach_marker {
+            if debug {
                 frame.print();
+                print_markers(&frame, &camera_model, &robot);
+            }
+
+            let found = frame.markers().iter().find(|m| m.id == id);
+            match found.and_then(|marker| Some((marker, marker.pose?))) {
+                Some((marker, pose)) => {
+                    let bearing = camera_model.bearing(
+                        marker.coordinate(),
+                        frame.dimensions(),
+                        robot.servo_angle(Servo::CameraPan),
+                        robot.servo_angle(Servo::CameraTilt),
+                    );
 
-                _ = robot.set_all_lights(LightColor::green());
+                    _ = robot.set_all_lights(LightColor::green());
+
+                    if time_since_last_action > Duration::from_millis(50) {
+                        last_action_time = SystemTime::now();
+                        robot.approach_action(bearing, pose.distance(), &range_settings, &odometry)
+                    }
+                }
 
-                if time_since_last_action > Duration::from_millis(50) {
-                    last_action_time = SystemTime::now();
-                    robot.green_action(frame.color_coordinate(), frame.dimensions())
+                // Wait for the marker to come into view
+                None => {
+                    _ = robot.stop();
+                    _ = robot.set_all_lights(LightColor::white());
                 }
             }
 
-            ClosestColor::Blue => {
+            continue;
+        }
+
+        motion.update(&frame, captured, robot.last_driven());
+
+        if sentry {
+            if debug {
                 frame.print();
+                motion.print();
+            }
+
+            match motion.blobs().first() {
+                Some(blob) => {
+                    let bearing = camera_model.bearing(
+                        blob.centroid,
+                        frame.dimensions(),
+                        robot.servo_angle(Servo::CameraPan),
+                        robot.servo_angle(Servo::CameraTilt),
+                    );
+
+                    _ = robot.set_all_lights(LightColor::red());
+
+                    if time_since_last_action > Duration::from_millis(500) {
+                        last_action_time = SystemTime::now();
+                        robot.sentry_action(bearing, &odometry)
+                    }
+                }
+
+                None => {
+                    _ = robot.set_all_lights(LightColor::black());
+                    robot.idle_action()
+                }
+            }
+
+            continue;
+        }
+
+        tracker.update(&frame, captured);
+
+        let decision = decisions.update(&frame, target);
+        match decision.and_then(|d| Some((d, frame.class(d.class)?))) {
+            Some((decision, class)) => {
+                let followed = if follow_track {
+                    tracker.follow(decision.class, target)
+                } else {
+                    None
+                };
+                let coordinate = followed.map_or(decision.coordinate, |t| t.coordinate());
+                let bearing = camera_model.bearing(
+                    coordinate,
+                    frame.dimensions(),
+                    robot.servo_angle(Servo::CameraPan),
+                    robot.servo_angle(Servo::CameraTilt),
+                );
+
+                let blob = followed
+                    .and_then(|t| t.blob)
+                    .or_else(|| frame.target_blob(decision.class, target).copied());
+                let shape = blob.map_or(Shape::Unknown, |b| b.shape());
+
+                // From how big the target looks, for classes of a known size
+                let distance = blob.and_then(|blob| {
+                    range_settings.distance(&blob, &class.name, frame.dimensions(), &camera_model)
+                });
+
+                frame.print();
+                if debug {
+                    tracker.print(&frame);
+                    print_markers(&frame, &camera_model, &robot);
+                    motion.print();
+                    stall.print();
+                    odometry.print();
+                }
+                println!("The target is a {} at {}", shape, bearing);
+
+                // Where the bottom of the target touches the floor
+                if let Some(ground_plane) = &ground_plane
+                    && let Some(blob) = frame.target_blob(decision.class, target)
+                    && let Some((forward, left)) = ground_plane.floor_position(
+                        blob.bottom_center(),
+                        frame.dimensions(),
+                        &camera_model,
+                        robot.servo_angle(Servo::CameraPan),
+                        robot.servo_angle(Servo::CameraTilt),
+                    )
+                {
+                    println!("It is {:.0} cm ahead and {:.0} cm to the left", forward, left);
+                }
+
+                if let Some(distance) = distance {
+                    println!("It is {:.0} cm from the camera", distance);
+                }
+
+                _ = robot.set_all_lights(class_light(class));
+
+                match (class.name.as_str(), shape) {
+                    _ if approach && let Some(distance) = distance => {
+                        if time_since_last_action > Duration::from_millis(50) {
+                            last_action_time = SystemTime::now();
+                            robot.approach_action(bearing, distance, &range_settings, &odometry)
+                        }
+                    }
+
+                    // A red box is in the way rather than something to react to
+                    ("red", Shape::Square | Shape::Rectangle)
+                        if blob.is_some_and(|b| is_obstacle(&b, frame.dimensions())) =>
+                    {
+                        if time_since_last_action > Duration::from_millis(50) {
+                            last_action_time = SystemTime::now();
+                            robot.avoid_action(bearing)
+                        }
+                    }
+
+                    ("red", _) => {
+                        if time_since_last_action > Duration::from_millis(2000) {
+                            last_action_time = SystemTime::now();
+                            robot.red_action()
+                        }
+                    }
+
+                    ("green", _) => {
+                        if time_since_last_action > Duration::from_millis(50) {
+                            last_action_time = SystemTime::now();
+                            robot.green_action(bearing)
+                        }
+                    }
 
-                _ = robot.set_all_lights(LightColor::blue());
+                    ("blue", _) => {
+                        if time_since_last_action > Duration::from_millis(50) {
+                            last_action_time = SystemTime::now();
+                            robot.blue_action(bearing)
+                        }
+                    }
 
-                if time_since_last_action > Duration::from_millis(50) {
-                    last_action_time = SystemTime::now();
-                    robot.blue_action(frame.color_coordinate(), frame.dimensions())
+                    // Classes without an action of their own only show their light
+                    _ => robot.idle_action(),
                 }
             }
 
-            ClosestColor::None => {
+            None => {
                 if debug {
                     frame.print();
+                    tracker.print(&frame);
+                    print_markers(&frame, &camera_model, &robot);
+                    motion.print();
+                    stall.print();
+                    odometry.print();
                 }
 
                 _ = robot.set_all_lights(LightColor::white());
@@ -0,0 +1,208 @@
+use std::cmp::Reverse;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::camera::{Blob, Frame, LUMA_SCALE, NO_CLASS, find_regions};
+use crate::config::Config;
+
+const MOTION_FILE: &str = "motion.conf";
+
+// Value of moving pixels in the motion mask, every other pixel is NO_CLASS
+const MOVING: u8 = 0;
+
+// How much slower the background learns pixels that are moving, so that
+// something that stops still fades into it instead of staying in motion
+// forever
+const MOVING_LEARNING_FACTOR: f32 = 0.1;
+
+#[derive(Clone, Copy)]
+pub struct MotionSettings {
+    // How quickly the background takes on the current frame, from 0 to 1
+    pub learning_rate: f32,
+    // Difference from the background at which a pixel is moving, in luma
+    // levels
+    pub threshold: f32,
+    // Smallest moving region reported, in full resolution pixels
+    pub min_area: usize,
+    // How long after the wheels stop frames are still ignored, for the
+    // robot to stop rocking and the exposure to settle
+    pub settle_time: Duration,
+    // Frames the background learns from before motion is reported
+    pub warmup_frames: u32,
+    // Fraction of the frame that changing at once is taken as the lighting
+    // or the camera changing rather than motion, and starts the background
+    // over
+    pub max_fraction: f32,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        Self {
+            learning_rate: 0.05,
+            threshold: 25.0,
+            min_area: 400,
+            settle_time: Duration::from_millis(500),
+            warmup_frames: 5,
+            max_fraction: 0.5,
+        }
+    }
+}
+
+impl MotionSettings {
+    // motion.conf:
+    //
+    // learning_rate = 0.05
+    // threshold = 25
+    // min_area = 400
+    // settle_time = 500
+    // warmup_frames = 5
+    // max_fraction = 0.5
+    //
+    // The settle time is in milliseconds
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            learning_rate: config.parse_or("", "learning_rate", defaults.learning_rate)?,
+            threshold: config.parse_or("", "threshold", defaults.threshold)?,
+            min_area: config.parse_or("", "min_area", defaults.min_area)?,
+            settle_time: Duration::from_millis(config.parse_or(
+                "",
+                "settle_time",
+                defaults.settle_time.as_millis() as u64,
+            )?),
+            warmup_frames: config.parse_or("", "warmup_frames", defaults.warmup_frames)?,
+            max_fraction: config.parse_or("", "max_fraction", defaults.max_fraction)?,
+        };
+
+        if settings.learning_rate <= 0.0 || settings.learning_rate > 1.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the learning rate must be above 0 and at most 1",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(MOTION_FILE, "motion settings", Self::parse)
+    }
+}
+
+// Finds what is moving in front of a still robot by comparing each frame's
+// luma with a running average of the frames before it
+pub struct MotionDetector {
+    settings: MotionSettings,
+    // Average luma of every pixel of the luma image, empty until the first
+    // frame after a reset
+    background: Vec<f32>,
+    dimensions: (usize, usize),
+    // Frames learned since the background was started over
+    learned: u32,
+    // Regions of moving pixels in the last frame, largest first. Their class
+    // is meaningless
+    blobs: Vec<Blob>,
+}
+
+impl MotionDetector {
+    pub fn new(settings: MotionSettings) -> Self {
+        Self {
+            settings,
+            background: Vec::new(),
+            dimensions: (0, 0),
+            learned: 0,
+            blobs: Vec::new(),
+        }
+    }
+
+    // Forgets the background, for when the view has changed
+    pub fn reset(&mut self) {
+        self.background.clear();
+        self.learned = 0;
+        self.blobs.clear();
+    }
+
+    // `captured` is when the frame was taken and `last_driven` when the
+    // wheels were last turning. Everything in the frame moves while the robot
+    // does, so those frames are skipped and the background is learned again
+    // once it is still
+    pub fn update(&mut self, frame: &Frame, captured: Instant, last_driven: Option<Instant>) {
+        if last_driven.is_some_and(|t| captured < t + self.settings.settle_time) {
+            self.reset();
+            return;
+        }
+
+        let luma = frame.luma_image();
+        let (width, height) = luma.dimensions();
+        if self.background.is_empty() || self.dimensions != (width, height) {
+            self.background = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| f32::from(luma.get(x, y)))
+                .collect();
+            self.dimensions = (width, height);
+            self.learned = 1;
+            self.blobs.clear();
+            return;
+        }
+
+        // Learn quickly at first, then settle on the learning rate
+        self.learned = self.learned.saturating_add(1);
+        let rate = (1.0 / self.learned as f32).max(self.settings.learning_rate);
+
+        let mut mask = vec![NO_CLASS; width * height];
+        let mut moving = 0;
+        for y in 0..height {
+            for x in 0..width {
+                let index = y * width + x;
+                let value = f32::from(luma.get(x, y));
+                let background = &mut self.background[index];
+
+                let pixel_rate = if (value - *background).abs() > self.settings.threshold {
+                    mask[index] = MOVING;
+                    moving += 1;
+                    rate * MOVING_LEARNING_FACTOR
+                } else {
+                    rate
+                };
+
+                *background += (value - *background) * pixel_rate;
+            }
+        }
+
+        if moving as f32 > self.settings.max_fraction * (width * height) as f32 {
+            self.reset();
+            return;
+        }
+
+        if self.learned < self.settings.warmup_frames {
+            self.blobs.clear();
+            return;
+        }
+
+        // Coordinates are upright like the frame's, but not undistorted when
+        // only coordinates are
+        self.blobs = find_regions(&mask, self.dimensions, LUMA_SCALE)
+            .into_iter()
+            .filter(|b| b.area >= self.settings.min_area)
+            .collect();
+        self.blobs.sort_by_key(|b| Reverse(b.area));
+    }
+
+    // Moving regions, largest first
+    pub fn blobs(&self) -> &[Blob] {
+        &self.blobs
+    }
+
+    pub fn print(&self) {
+        if self.learned < self.settings.warmup_frames {
+            println!("Learning the background");
+        }
+
+        for blob in &self.blobs {
+            println!(
+                "Motion at ({}, {}) covering {} pixels",
+                blob.centroid.0, blob.centroid.1, blob.area
+            );
+        }
+    }
+}
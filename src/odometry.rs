@@ -0,0 +1,212 @@
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::camera::{CameraModel, Frame, Pyramid, estimate_shift};
+use crate::config::Config;
+use crate::control::movement::Drive;
+
+const ODOMETRY_FILE: &str = "odometry.conf";
+
+// Longest a single turn is commanded for, so a bad turn rate can't send the
+// robot spinning
+const MAX_TURN_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy)]
+pub struct OdometrySettings {
+    // How far from the predicted shift the view is searched, in pixels at an
+    // eighth of the resolution
+    pub search_radius: usize,
+    // Degrees per second the robot turns at full speed, before it has been
+    // measured
+    pub turn_rate: f32,
+    // How quickly the turn rate follows each measured turn, from 0 to 1
+    pub learning_rate: f32,
+    // Smallest commanded turn, in degrees, that the turn rate is learned from
+    pub min_turn: f32,
+}
+
+impl Default for OdometrySettings {
+    fn default() -> Self {
+        Self {
+            search_radius: 8,
+            turn_rate: 180.0,
+            learning_rate: 0.3,
+            min_turn: 5.0,
+        }
+    }
+}
+
+impl OdometrySettings {
+    // odometry.conf:
+    //
+    // search_radius = 8
+    // turn_rate = 180
+    // learning_rate = 0.3
+    // min_turn = 5
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            search_radius: config.parse_or("", "search_radius", defaults.search_radius)?,
+            turn_rate: config.parse_or("", "turn_rate", defaults.turn_rate)?,
+            learning_rate: config.parse_or("", "learning_rate", defaults.learning_rate)?,
+            min_turn: config.parse_or("", "min_turn", defaults.min_turn)?,
+        };
+
+        if settings.turn_rate <= 0.0 || settings.learning_rate < 0.0 || settings.learning_rate > 1.0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the turn rate must be above 0 and the learning rate from 0 to 1",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(ODOMETRY_FILE, "odometry settings", Self::parse)
+    }
+}
+
+// Seconds of a drive that fall between two instants
+fn overlap(drive: &Drive, from: Instant, to: Instant) -> f32 {
+    let start = drive.started.max(from);
+    let end = drive.stopped.map_or(to, |stopped| stopped.min(to));
+    end.saturating_duration_since(start).as_secs_f32()
+}
+
+// How far the robot actually turned, from how far the view shifted between
+// consecutive frames. Learns how fast the robot turns, so turns can be timed
+// to reach an angle. The view is only matched between frames the wheels were
+// driven between, since matching costs too much to run on every frame
+pub struct Odometry {
+    settings: OdometrySettings,
+    previous: Option<(Instant, Pyramid)>,
+    // Whether the wheels were driven between the last two frames
+    driven: bool,
+    // Of the view between the last two frames, in pixels
+    shift: Option<(f32, f32)>,
+    // Degrees clockwise between the last two frames, and per second
+    yaw: Option<f32>,
+    yaw_rate: Option<f32>,
+    // Degrees turned clockwise since the start
+    heading: f32,
+    // Learned degrees per second at full speed
+    turn_rate: f32,
+}
+
+impl Odometry {
+    pub fn new(settings: OdometrySettings) -> Self {
+        Self {
+            settings,
+            previous: None,
+            driven: false,
+            shift: None,
+            yaw: None,
+            yaw_rate: None,
+            heading: 0.0,
+            turn_rate: settings.turn_rate,
+        }
+    }
+
+    // Degrees the robot should turn over a drive at the learned rate, for the
+    // part of it between two instants
+    fn predicted_yaw(&self, drive: &Drive, from: Instant, to: Instant) -> f32 {
+        drive.turn * self.turn_rate * f32::from(drive.speed) / 255.0 * overlap(drive, from, to)
+    }
+
+    // `captured` is when the frame was taken and `drive` the last motion the
+    // wheels were asked to make, which says where to look for the view. The
+    // servo angles are those the camera was at for both frames
+    pub fn update(
+        &mut self,
+        frame: &Frame,
+        captured: Instant,
+        drive: Option<Drive>,
+        model: &CameraModel,
+        servos: (Option<u8>, Option<u8>),
+    ) {
+        let pyramid = Pyramid::new(frame.luma_image());
+        let Some((previous_captured, previous)) = self.previous.replace((captured, pyramid))
+        else {
+            return;
+        };
+        let Some((_, current)) = &self.previous else {
+            return;
+        };
+
+        // A still robot didn't turn
+        self.driven = drive.is_some_and(|d| overlap(&d, previous_captured, captured) > 0.0);
+        if !self.driven {
+            self.shift = None;
+            self.yaw = Some(0.0);
+            self.yaw_rate = Some(0.0);
+            return;
+        }
+
+        let dimensions = frame.dimensions();
+        let (pan, tilt) = servos;
+
+        let predicted_yaw =
+            drive.map_or(0.0, |d| self.predicted_yaw(&d, previous_captured, captured));
+        let predicted = model
+            .yaw_shift(predicted_yaw, dimensions, pan, tilt)
+            .unwrap_or((0.0, 0.0));
+
+        self.shift = estimate_shift(&previous, current, predicted, self.settings.search_radius);
+        self.yaw = self.shift.map(|shift| {
+            // A point that was at `middle - shift / 2` is now at
+            // `middle + shift / 2`, and it is as far anticlockwise as the
+            // robot turned clockwise
+            let middle = (dimensions.0 as f32 / 2.0, dimensions.1 as f32 / 2.0);
+            let before = (middle.0 - shift.0 / 2.0, middle.1 - shift.1 / 2.0);
+            let after = (middle.0 + shift.0 / 2.0, middle.1 + shift.1 / 2.0);
+
+            model.point_bearing(before, dimensions, pan, tilt).azimuth
+                - model.point_bearing(after, dimensions, pan, tilt).azimuth
+        });
+
+        let dt = captured.saturating_duration_since(previous_captured).as_secs_f32();
+        self.yaw_rate = self.yaw.filter(|_| dt > 0.0).map(|yaw| yaw / dt);
+
+        if let Some(yaw) = self.yaw {
+            self.heading += yaw;
+        }
+
+        // Learn from turns in place that happened entirely between the two
+        // frames, where the whole turn was seen
+        if let (Some(yaw), Some(drive)) = (self.yaw, drive)
+            && drive.forward == 0.0
+            && drive.strafe == 0.0
+            && drive.started > previous_captured
+            && drive.stopped.is_some_and(|stopped| stopped <= captured)
+            && predicted_yaw.abs() >= self.settings.min_turn
+            && yaw * predicted_yaw > 0.0
+        {
+            let measured = self.turn_rate * yaw / predicted_yaw;
+            self.turn_rate += (measured - self.turn_rate) * self.settings.learning_rate;
+        }
+    }
+
+    // How long to turn at `speed` to turn by `degrees`, at the learned rate
+    pub fn turn_time(&self, degrees: f32, speed: u8) -> Duration {
+        let rate = self.turn_rate * f32::from(speed.max(1)) / 255.0;
+        Duration::from_secs_f32(degrees.abs() / rate).min(MAX_TURN_TIME)
+    }
+
+    pub fn print(&self) {
+        match (self.shift, self.yaw_rate) {
+            _ if !self.driven => println!("The wheels weren't driven since the last frame"),
+            (Some(shift), Some(yaw_rate)) => println!(
+                "The view moved ({:.1}, {:.1}) pixels, turning {:.1}°/s",
+                shift.0, shift.1, yaw_rate
+            ),
+            _ => println!("The view couldn't be matched with the last frame"),
+        }
+
+        println!(
+            "Turned {:.0}° in total, turning {:.0}°/s at full speed",
+            self.heading, self.turn_rate
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use core::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel, sync_channel};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::camera::{
+    AutoExposure, CameraVideoStream, ColorClasses, ColorTable, Frame, FrameGeometry,
+    FrameSampler, LineDetector, MarkerDetector, MaskFilter, WhiteBalance,
+};
+
+// The capture thread and the control thread take one core each, so leave the
+// remaining two cores of the Pi to decoding
+const DECODE_WORKERS: usize = 2;
+
+// How long to wait between attempts to reopen a camera that has disappeared
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+// How long the control thread waits for a frame before checking whether the
+// camera is still there
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+
+// A compressed frame straight from the camera
+struct RawFrame {
+    sequence: u64,
+    captured: Instant,
+    jpeg: Vec<u8>,
+}
+
+#[derive(Clone, Default)]
+pub struct PipelineSettings {
+    // Camera profile to load, reapplied every time the camera is reopened
+    pub camera_profile: Option<String>,
+    // Drive manual exposure and gain from the frame brightness
+    pub auto_exposure: bool,
+    // Correct the chroma of every pixel using the gray world assumption
+    pub white_balance: bool,
+    // How frames are turned upright and undistorted before anything is
+    // measured in them
+    pub geometry: FrameGeometry,
+    // The color classes pixels are sorted into
+    pub classes: Arc<ColorClasses>,
+    // Look for markers in every frame, None to skip them
+    pub markers: Option<Arc<MarkerDetector>>,
+    // Look for the line being followed in every frame, None to skip it
+    pub line: Option<Arc<LineDetector>>,
+}
+
+// Requests from the control thread to the capture thread, which owns the camera
+enum CameraCommand {
+    ScaleExposure(f32),
+}
+
+pub enum PipelineError {
+    // The camera has disappeared and is being reopened
+    CameraLost,
+    // Every worker has stopped, no more frames will arrive
+    Stopped,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::CameraLost => write!(f, "The camera was lost"),
+            PipelineError::Stopped => write!(f, "The pipeline has stopped"),
+        }
+    }
+}
+
+pub struct ProcessedFrame {
+    pub sequence: u64,
+    pub captured: Instant,
+    pub frame: Frame,
+}
+
+// A channel that only ever holds the newest value. Putting a value while one
+// is still waiting replaces it, so a slow consumer always gets the freshest
+// frame instead of working through a backlog of stale ones
+struct Latest<T> {
+    slot: Mutex<Option<T>>,
+    ready: Condvar,
+}
+
+impl<T> Latest<T> {
+    fn new() -> Self {
+        Self {
+            slot: Mutex::new(None),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Returns true if an older value was replaced
+    fn put(&self, value: T) -> bool {
+        let replaced = self.slot.lock().unwrap().replace(value).is_some();
+        self.ready.notify_one();
+
+        replaced
+    }
+
+    // Like put, but keeps whichever of the two values is newer by `key`, for
+    // producers that can finish out of order. Returns true if either was
+    // dropped
+    fn put_newest<K: Ord>(&self, value: T, key: impl Fn(&T) -> K) -> bool {
+        let mut slot = self.slot.lock().unwrap();
+        let replaced = match slot.take() {
+            Some(old) if key(&old) > key(&value) => {
+                *slot = Some(old);
+                true
+            }
+            old => {
+                *slot = Some(value);
+                old.is_some()
+            }
+        };
+        self.ready.notify_one();
+
+        replaced
+    }
+
+    // Waits up to `timeout` for a value
+    fn take_timeout(&self, timeout: Duration) -> Option<T> {
+        let slot = self.slot.lock().unwrap();
+        let (mut slot, _) = self
+            .ready
+            .wait_timeout_while(slot, timeout, |slot| slot.is_none())
+            .unwrap();
+
+        slot.take()
+    }
+
+    // Blocks until a value is available
+    fn take(&self) -> T {
+        let mut slot = self.slot.lock().unwrap();
+        loop {
+            if let Some(value) = slot.take() {
+                return value;
+            }
+
+            slot = self.ready.wait(slot).unwrap();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct StageMetrics {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl StageMetrics {
+    fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    fn average(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+
+        self.total / self.count
+    }
+}
+
+impl fmt::Display for StageMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "avg {}ms, max {}ms",
+            self.average().as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Metrics {
+    // Time spent waiting on the driver for each frame
+    capture: StageMetrics,
+    // Time between capture and a worker picking the frame up
+    queue: StageMetrics,
+    decode: StageMetrics,
+    classify: StageMetrics,
+    // Total age of a frame by the time the control thread receives it
+    latency: StageMetrics,
+
+    // Frames replaced before a worker got to them
+    dropped_capture: u32,
+    // Frames decoded but superseded before the control thread read them
+    dropped_decoded: u32,
+    // Frames that failed to decode
+    corrupt: u32,
+    // Times the camera had to be reopened
+    reconnects: u32,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Pipeline metrics over {} frames:\n\
+            capture: {}\n\
+            queue: {}\n\
+            decode: {}\n\
+            classify: {}\n\
+            end to end: {}\n\
+            dropped {} frames before decoding and {} after\n\
+            {} corrupt frames, {} camera reconnects",
+            self.capture.count,
+            self.capture,
+            self.queue,
+            self.decode,
+            self.classify,
+            self.latency,
+            self.dropped_capture,
+            self.dropped_decoded,
+            self.corrupt,
+            self.reconnects
+        )
+    }
+}
+
+pub struct Pipeline {
+    // Only the newest decoded frame is kept, so frames decoded while the
+    // control thread was busy, e.g. during an action, are skipped
+    frames: Arc<Latest<ProcessedFrame>>,
+    last_sequence: Option<u64>,
+    metrics: Arc<Mutex<Metrics>>,
+    camera_connected: Arc<AtomicBool>,
+
+    settings: PipelineSettings,
+    camera_commands: Sender<CameraCommand>,
+    auto_exposure: AutoExposure,
+    white_balance: Arc<WhiteBalance>,
+}
+
+impl Pipeline {
+    pub fn start(settings: PipelineSettings) -> std::io::Result<Self> {
+        let raw_frames = Arc::new(Latest::new());
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let color_table = Arc::new(ColorTable::new(settings.classes.clone()));
+        let mask_filter = Arc::new(MaskFilter::load());
+        let camera_connected = Arc::new(AtomicBool::new(true));
+        let white_balance = Arc::new(WhiteBalance::new());
+        let (camera_commands, camera_command_receiver) = channel();
+
+        let frames = Arc::new(Latest::new());
+
+        // The stream borrows the device, so it has to be opened on the thread
+        // that owns it. Report back whether that worked before continuing
+        let (opened_sender, opened_receiver) = sync_channel(0);
+        {
+            let raw_frames = raw_frames.clone();
+            let metrics = metrics.clone();
+            let camera_connected = camera_connected.clone();
+            let settings = settings.clone();
+            thread::spawn(move || {
+                let camera_stream = match Self::open_camera(&settings) {
+                    Ok(s) => {
+                        _ = opened_sender.send(Ok(()));
+                        s
+                    }
+                    Err(e) => {
+                        _ = opened_sender.send(Err(e));
+                        return;
+                    }
+                };
+
+                Self::capture(
+                    camera_stream,
+                    &settings,
+                    &raw_frames,
+                    &metrics,
+                    &camera_connected,
+                    &camera_command_receiver,
+                );
+            });
+        }
+
+        opened_receiver.recv().unwrap()?;
+
+        for _ in 0..DECODE_WORKERS {
+            let raw_frames = raw_frames.clone();
+            let frames = frames.clone();
+            let metrics = metrics.clone();
+            let color_table = color_table.clone();
+            let mask_filter = mask_filter.clone();
+            let settings = settings.clone();
+            let white_balance = white_balance.clone();
+            thread::spawn(move || {
+                Self::decode(
+                    &raw_frames,
+                    &frames,
+                    &metrics,
+                    &color_table,
+                    &mask_filter,
+                    &settings,
+                    &white_balance,
+                )
+            });
+        }
+
+        Ok(Pipeline {
+            frames,
+            last_sequence: None,
+            metrics,
+            camera_connected,
+
+            settings,
+            camera_commands,
+            auto_exposure: AutoExposure::new(),
+            white_balance,
+        })
+    }
+
+    fn open_camera<'stream>(
+        settings: &PipelineSettings,
+    ) -> std::io::Result<CameraVideoStream<'stream>> {
+        let camera_stream = CameraVideoStream::new()?;
+
+        if let Some(name) = &settings.camera_profile
+            && let Err(e) = camera_stream.load_profile(name)
+        {
+            println!("Failed to load camera profile {}: {}", name, e);
+        }
+
+        if settings.auto_exposure
+            && let Err(e) = camera_stream.set_manual_exposure()
+        {
+            println!("Failed to set exposure to manual: {}", e);
+        }
+
+        Ok(camera_stream)
+    }
+
+    fn capture(
+        mut camera_stream: CameraVideoStream,
+        settings: &PipelineSettings,
+        raw_frames: &Latest<RawFrame>,
+        metrics: &Mutex<Metrics>,
+        camera_connected: &AtomicBool,
+        camera_commands: &Receiver<CameraCommand>,
+    ) {
+        let mut sequence = 0;
+        loop {
+            while let Ok(command) = camera_commands.try_recv() {
+                match command {
+                    CameraCommand::ScaleExposure(ratio) => {
+                        if let Err(e) = camera_stream.scale_exposure(ratio) {
+                            println!("Failed to adjust the exposure: {}", e);
+                        }
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            let jpeg = match camera_stream.get_next_jpeg() {
+                Ok(j) => j,
+                Err(e) => {
+                    println!("Failed to capture a frame: {}", e);
+
+                    camera_connected.store(false, Ordering::Relaxed);
+                    drop(camera_stream);
+                    camera_stream = Self::reconnect(settings);
+                    camera_connected.store(true, Ordering::Relaxed);
+
+                    metrics.lock().unwrap().reconnects += 1;
+                    continue;
+                }
+            };
+            let capture_time = now.elapsed();
+
+            let replaced = raw_frames.put(RawFrame {
+                sequence,
+                captured: Instant::now(),
+                jpeg,
+            });
+            sequence += 1;
+
+            let mut metrics = metrics.lock().unwrap();
+            metrics.capture.record(capture_time);
+            if replaced {
+                metrics.dropped_capture += 1;
+            }
+        }
+    }
+
+    // Keeps trying to reopen the camera, backing off exponentially so an
+    // unplugged camera doesn't spin a core
+    fn reconnect<'stream>(settings: &PipelineSettings) -> CameraVideoStream<'stream> {
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        loop {
+            sleep(backoff);
+
+            match Self::open_camera(settings) {
+                Ok(s) => {
+                    println!("Reopened the camera stream");
+                    return s;
+                }
+                Err(e) => {
+                    println!(
+                        "Failed to reopen the camera stream, retrying in {}ms: {}",
+                        backoff.as_millis(),
+                        e
+                    );
+                }
+            }
+
+            backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+        }
+    }
+
+    fn decode(
+        raw_frames: &Latest<RawFrame>,
+        frames: &Latest<ProcessedFrame>,
+        metrics: &Mutex<Metrics>,
+        color_table: &ColorTable,
+        mask_filter: &MaskFilter,
+        settings: &PipelineSettings,
+        white_balance: &WhiteBalance,
+    ) {
+        // Each worker keeps its own lookup maps
+        let mut sampler = FrameSampler::new(settings.geometry);
+        loop {
+            let raw_frame = raw_frames.take();
+            let queue_time = raw_frame.captured.elapsed();
+            let chroma_offset = white_balance.offset();
+            let frame = match Frame::decode(
+                &raw_frame.jpeg,
+                color_table,
+                mask_filter,
+                &mut sampler,
+                settings.markers.as_deref(),
+                settings.line.as_deref(),
+                chroma_offset,
+            ) {
+                Ok(f) => f,
+                Err(e) => {
+                    println!("Skipping a corrupt frame: {}", e);
+                    metrics.lock().unwrap().corrupt += 1;
+                    continue;
+                }
+            };
+
+            {
+                let mut metrics = metrics.lock().unwrap();
+                metrics.queue.record(queue_time);
+                metrics.decode.record(frame.decode_time());
+                metrics.classify.record(frame.classify_time());
+            }
+
+            let processed = ProcessedFrame {
+                sequence: raw_frame.sequence,
+                captured: raw_frame.captured,
+                frame,
+            };
+
+            if frames.put_newest(processed, |f| f.sequence) {
+                metrics.lock().unwrap().dropped_decoded += 1;
+            }
+        }
+    }
+
+    // Blocks until a frame is ready, then returns the newest one that has
+    // been decoded. Returns early with an error while the camera is being
+    // reopened
+    pub fn next_frame(&mut self) -> Result<ProcessedFrame, PipelineError> {
+        let newest = loop {
+            let Some(frame) = self.frames.take_timeout(FRAME_TIMEOUT) else {
+                if !self.camera_connected.load(Ordering::Relaxed) {
+                    return Err(PipelineError::CameraLost);
+                }
+
+                // Only the pipeline itself is left holding the slot
+                if Arc::strong_count(&self.frames) == 1 {
+                    return Err(PipelineError::Stopped);
+                }
+
+                continue;
+            };
+
+            // Workers can finish out of order, never go back in time
+            if self.last_sequence.is_some_and(|s| frame.sequence <= s) {
+                self.metrics.lock().unwrap().dropped_decoded += 1;
+                continue;
+            }
+
+            break frame;
+        };
+
+        self.last_sequence = Some(newest.sequence);
+
+        if self.settings.auto_exposure
+            && let Some(ratio) = self.auto_exposure.update(&newest.frame)
+        {
+            _ = self
+                .camera_commands
+                .send(CameraCommand::ScaleExposure(ratio));
+        }
+
+        if self.settings.white_balance {
+            self.white_balance.update(&newest.frame);
+        }
+
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.latency.record(newest.captured.elapsed());
+
+        Ok(newest)
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        *self.metrics.lock().unwrap()
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::camera::Frame;
+use crate::config::Config;
+use crate::control::movement::Drive;
+
+const STALL_FILE: &str = "stall.conf";
+
+#[derive(Clone, Copy)]
+pub struct StallSettings {
+    // Average change in luma between the frames before and after a drive
+    // below which the robot didn't move
+    pub min_change: f32,
+    // Shortest and slowest drives that are checked, since short or slow ones
+    // might not move the view enough to tell
+    pub min_duration: Duration,
+    pub min_speed: u8,
+    // How long after the wheels stop the frame after a drive is taken, for
+    // the robot to stop rocking
+    pub settle_time: Duration,
+    // Drives in a row that have to leave the view unchanged
+    pub stalled_drives: u32,
+    // How hard and for how long the robot backs away from a stall
+    pub backoff_speed: u8,
+    pub backoff_time: Duration,
+    // How long the error light stays on and the robot holds still after
+    // backing off
+    pub recovery_time: Duration,
+}
+
+impl Default for StallSettings {
+    fn default() -> Self {
+        Self {
+            min_change: 4.0,
+            min_duration: Duration::from_millis(150),
+            min_speed: 30,
+            settle_time: Duration::from_millis(200),
+            stalled_drives: 2,
+            backoff_speed: 80,
+            backoff_time: Duration::from_millis(500),
+            recovery_time: Duration::from_secs(2),
+        }
+    }
+}
+
+// A duration in milliseconds
+fn parse_millis(config: &Config, key: &str, default: Duration) -> std::io::Result<Duration> {
+    Ok(Duration::from_millis(config.parse_or("", key, default.as_millis() as u64)?))
+}
+
+impl StallSettings {
+    // stall.conf:
+    //
+    // min_change = 4
+    // min_duration = 150
+    // min_speed = 30
+    // settle_time = 200
+    // stalled_drives = 2
+    // backoff_speed = 80
+    // backoff_time = 500
+    // recovery_time = 2000
+    //
+    // Times are in milliseconds
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            min_change: config.parse_or("", "min_change", defaults.min_change)?,
+            min_duration: parse_millis(config, "min_duration", defaults.min_duration)?,
+            min_speed: config.parse_or("", "min_speed", defaults.min_speed)?,
+            settle_time: parse_millis(config, "settle_time", defaults.settle_time)?,
+            stalled_drives: config.parse_or("", "stalled_drives", defaults.stalled_drives)?,
+            backoff_speed: config.parse_or("", "backoff_speed", defaults.backoff_speed)?,
+            backoff_time: parse_millis(config, "backoff_time", defaults.backoff_time)?,
+            recovery_time: parse_millis(config, "recovery_time", defaults.recovery_time)?,
+        };
+
+        if settings.stalled_drives == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "stalled_drives must be above 0",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(STALL_FILE, "stall settings", Self::parse)
+    }
+}
+
+// A frame taken while the wheels were still
+struct StillFrame {
+    captured: Instant,
+    luma: Vec<u8>,
+    dimensions: (usize, usize),
+}
+
+// Notices the robot pushing against something: the wheels were driven but
+// the view is the same before and after. Actions block while the wheels
+// turn, so rather than the frames during a drive, the last frame before it is
+// compared with the first one after it
+pub struct StallDetector {
+    settings: StallSettings,
+    reference: Option<StillFrame>,
+    // Start of the last drive that was checked, so each is only checked once
+    checked: Option<Instant>,
+    // Drives in a row that left the view unchanged
+    stalled: u32,
+    last_stall: Option<Instant>,
+    // Average change in luma over the last drive checked
+    change: Option<f32>,
+}
+
+impl StallDetector {
+    pub fn new(settings: StallSettings) -> Self {
+        Self {
+            settings,
+            reference: None,
+            checked: None,
+            stalled: 0,
+            last_stall: None,
+            change: None,
+        }
+    }
+
+    // `captured` is when the frame was taken and `drive` the last motion the
+    // wheels were asked to make. Returns whether the robot has just been
+    // found stalled
+    pub fn update(&mut self, frame: &Frame, captured: Instant, drive: Option<Drive>) -> bool {
+        // Frames from while the wheels were turning or still settling show
+        // neither the view before nor after
+        if let Some(drive) = drive
+            && captured >= drive.started
+            && drive
+                .stopped
+                .is_none_or(|stopped| captured < stopped + self.settings.settle_time)
+        {
+            return false;
+        }
+
+        let luma = frame.luma_image();
+        let dimensions = luma.dimensions();
+        let pixels: Vec<u8> = (0..dimensions.1)
+            .flat_map(|y| (0..dimensions.0).map(move |x| (x, y)))
+            .map(|(x, y)| luma.get(x, y))
+            .collect();
+
+        // Only a frame taken after the drive has settled shows the view after
+        // it, a frame from before the drive can still be handed over late
+        let mut stalled = false;
+        if let Some(drive) = drive
+            && drive
+                .stopped
+                .is_some_and(|stopped| captured >= stopped + self.settings.settle_time)
+            && self.checked != Some(drive.started)
+            && let Some(reference) = &self.reference
+            && reference.captured < drive.started
+            && reference.dimensions == dimensions
+            && !pixels.is_empty()
+        {
+            self.checked = Some(drive.started);
+
+            let total: u64 = pixels
+                .iter()
+                .zip(&reference.luma)
+                .map(|(&a, &b)| u64::from(a.abs_diff(b)))
+                .sum();
+            let change = total as f32 / pixels.len() as f32;
+            self.change = Some(change);
+
+            let counts = drive.speed >= self.settings.min_speed
+                && drive.duration().is_some_and(|d| d >= self.settings.min_duration);
+            if counts {
+                if change < self.settings.min_change {
+                    self.stalled += 1;
+                } else {
+                    self.stalled = 0;
+                }
+            }
+
+            if self.stalled >= self.settings.stalled_drives {
+                self.stalled = 0;
+                self.last_stall = Some(captured);
+                stalled = true;
+            }
+        }
+
+        self.reference = Some(StillFrame {
+            captured,
+            luma: pixels,
+            dimensions,
+        });
+
+        stalled
+    }
+
+    // Whether the robot is still recovering from the last stall
+    pub fn recovering(&self) -> bool {
+        self.last_stall
+            .is_some_and(|stall| stall.elapsed() < self.settings.recovery_time)
+    }
+
+    pub fn print(&self) {
+        if let Some(change) = self.change {
+            println!(
+                "The last drive changed the view by {:.1} luma levels, {} stalled in a row",
+                change, self.stalled
+            );
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+
+use crate::camera::{Blob, BlobTarget, Frame};
+use crate::config::Config;
+
+const TRACKER_FILE: &str = "tracker.conf";
+
+// Uncertainty of a new track's velocity, in (pixels per second) squared. Large,
+// since nothing is known about it yet
+const INITIAL_VELOCITY_VARIANCE: f32 = 250_000.0;
+
+// Longest gap between frames that is predicted over, so a stall in the
+// pipeline doesn't throw every track off the edge of the frame
+const MAX_PREDICTION_STEP: f32 = 0.5;
+
+#[derive(Clone, Copy)]
+pub struct TrackerSettings {
+    // Furthest a blob can be from a track's predicted position and still be
+    // matched to it, in pixels
+    pub gate: f32,
+    // Frames a track survives without a matching blob, to ride out brief
+    // occlusions
+    pub max_misses: u32,
+    // Matches a new track needs before it is reported
+    pub min_hits: u32,
+    // How much the velocity is expected to change, in pixels per second
+    // squared
+    pub process_noise: f32,
+    // How far a blob's centroid jumps around a still target, in pixels
+    pub measurement_noise: f32,
+}
+
+impl Default for TrackerSettings {
+    fn default() -> Self {
+        Self {
+            gate: 150.0,
+            max_misses: 10,
+            min_hits: 3,
+            process_noise: 400.0,
+            measurement_noise: 8.0,
+        }
+    }
+}
+
+impl TrackerSettings {
+    // tracker.conf:
+    //
+    // gate = 150
+    // max_misses = 10
+    // min_hits = 3
+    // process_noise = 400
+    // measurement_noise = 8
+    fn parse(config: &Config) -> std::io::Result<Self> {
+        let defaults = Self::default();
+        let settings = Self {
+            gate: config.parse_or("", "gate", defaults.gate)?,
+            max_misses: config.parse_or("", "max_misses", defaults.max_misses)?,
+            min_hits: config.parse_or("", "min_hits", defaults.min_hits)?,
+            process_noise: config.parse_or("", "process_noise", defaults.process_noise)?,
+            measurement_noise: config.parse_or(
+                "",
+                "measurement_noise",
+                defaults.measurement_noise,
+            )?,
+        };
+
+        if settings.process_noise <= 0.0 || settings.measurement_noise <= 0.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the tracker noise values must be above 0",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn load() -> Self {
+        Config::load_or_default(TRACKER_FILE, "tracker settings", Self::parse)
+    }
+}
+
+// Constant velocity Kalman filter along one image axis. The axes are treated
+// as independent, which keeps every matrix 2x2
+#[derive(Clone, Copy)]
+struct AxisFilter {
+    position: f32,
+    velocity: f32,
+    // Covariance of (position, velocity)
+    covariance: [[f32; 2]; 2],
+}
+
+impl AxisFilter {
+    fn new(position: f32, measurement_noise: f32) -> Self {
+        Self {
+            position,
+            velocity: 0.0,
+            covariance: [
+                [measurement_noise.powi(2), 0.0],
+                [0.0, INITIAL_VELOCITY_VARIANCE],
+            ],
+        }
+    }
+
+    // Moves the state forward by `dt` seconds, with the velocity allowed to
+    // drift by white noise acceleration
+    fn predict(&mut self, dt: f32, process_noise: f32) {
+        let [[pp, pv], [vp, vv]] = self.covariance;
+        let q = process_noise.powi(2);
+
+        self.position += self.velocity * dt;
+        self.covariance = [
+            [
+                pp + dt * (pv + vp) + dt * dt * vv + q * dt.powi(3) / 3.0,
+                pv + dt * vv + q * dt * dt / 2.0,
+            ],
+            [
+                vp + dt * vv + q * dt * dt / 2.0,
+                vv + q * dt,
+            ],
+        ];
+    }
+
+    fn correct(&mut self, measured: f32, measurement_noise: f32) {
+        let [[pp, pv], [vp, vv]] = self.covariance;
+
+        let innovation = measured - self.position;
+        let variance = pp + measurement_noise.powi(2);
+        let gain = (pp / variance, vp / variance);
+
+        self.position += gain.0 * innovation;
+        self.velocity += gain.1 * innovation;
+        self.covariance = [
+            [(1.0 - gain.0) * pp, (1.0 - gain.0) * pv],
+            [vp - gain.1 * pp, vv - gain.1 * pv],
+        ];
+    }
+}
+
+pub struct Track {
+    pub id: u32,
+    pub class: u8,
+    x: AxisFilter,
+    y: AxisFilter,
+    // The blob matched in the latest frame, None while the track is coasting
+    // on its prediction
+    pub blob: Option<Blob>,
+    hits: u32,
+    misses: u32,
+}
+
+impl Track {
+    fn new(id: u32, blob: Blob, settings: &TrackerSettings) -> Self {
+        Self {
+            id,
+            class: blob.class,
+            x: AxisFilter::new(blob.centroid.0 as f32, settings.measurement_noise),
+            y: AxisFilter::new(blob.centroid.1 as f32, settings.measurement_noise),
+            blob: Some(blob),
+            hits: 1,
+            misses: 0,
+        }
+    }
+
+    // Estimated (or while coasting, predicted) centroid
+    pub fn position(&self) -> (f32, f32) {
+        (self.x.position, self.y.position)
+    }
+
+    // In pixels per second
+    pub fn velocity(&self) -> (f32, f32) {
+        (self.x.velocity, self.y.velocity)
+    }
+
+    pub fn coordinate(&self) -> (usize, usize) {
+        let (x, y) = self.position();
+        (x.round().max(0.0) as usize, y.round().max(0.0) as usize)
+    }
+
+    fn confirmed(&self, settings: &TrackerSettings) -> bool {
+        self.hits >= settings.min_hits
+    }
+}
+
+// Follows blobs from frame to frame, giving each object a stable id
+pub struct Tracker {
+    settings: TrackerSettings,
+    tracks: Vec<Track>,
+    next_id: u32,
+    last_update: Option<Instant>,
+    // The track actions are following
+    followed: Option<u32>,
+}
+
+impl Tracker {
+    pub fn new(settings: TrackerSettings) -> Self {
+        Self {
+            settings,
+            tracks: Vec::new(),
+            next_id: 1,
+            last_update: None,
+            followed: None,
+        }
+    }
+
+    // `captured` is when the frame was taken, which sets how far the tracks
+    // are predicted forward
+    pub fn update(&mut self, frame: &Frame, captured: Instant) {
+        self.match_blobs(frame.all_blobs(), captured);
+    }
+
+    fn match_blobs(&mut self, blobs: &[Blob], captured: Instant) {
+        let dt = match self.last_update {
+            Some(last) => captured
+                .saturating_duration_since(last)
+                .as_secs_f32()
+                .min(MAX_PREDICTION_STEP),
+            None => 0.0,
+        };
+        self.last_update = Some(captured);
+
+        for track in &mut self.tracks {
+            track.x.predict(dt, self.settings.process_noise);
+            track.y.predict(dt, self.settings.process_noise);
+        }
+
+        // Greedily match the closest track and blob pairs of the same class
+        let mut pairs = Vec::new();
+        for (t, track) in self.tracks.iter().enumerate() {
+            let (x, y) = track.position();
+            for (b, blob) in blobs.iter().enumerate() {
+                if blob.class != track.class {
+                    continue;
+                }
+
+                let distance =
+                    (blob.centroid.0 as f32 - x).hypot(blob.centroid.1 as f32 - y);
+                if distance <= self.settings.gate {
+                    pairs.push((distance, t, b));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut track_matches = vec![None; self.tracks.len()];
+        let mut blob_matched = vec![false; blobs.len()];
+        for (_, t, b) in pairs {
+            if track_matches[t].is_none() && !blob_matched[b] {
+                track_matches[t] = Some(b);
+                blob_matched[b] = true;
+            }
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(track_matches) {
+            match matched {
+                Some(b) => {
+                    let blob = blobs[b];
+                    track.x.correct(blob.centroid.0 as f32, self.settings.measurement_noise);
+                    track.y.correct(blob.centroid.1 as f32, self.settings.measurement_noise);
+                    track.blob = Some(blob);
+                    track.hits += 1;
+                    track.misses = 0;
+                }
+                None => {
+                    track.blob = None;
+                    track.misses += 1;
+                }
+            }
+        }
+
+        let max_misses = self.settings.max_misses;
+        self.tracks.retain(|t| t.misses <= max_misses);
+
+        for (blob, _) in blobs.iter().zip(blob_matched).filter(|(_, matched)| !matched) {
+            self.tracks.push(Track::new(self.next_id, *blob, &self.settings));
+            self.next_id += 1;
+        }
+    }
+
+    // Tracks that have been seen for long enough to trust
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.iter().filter(|t| t.confirmed(&self.settings))
+    }
+
+    // Sticks with the followed track for as long as it lives, even when
+    // another blob of the class becomes a better target, and only then picks
+    // a new one
+    pub fn follow(&mut self, class: u8, target: BlobTarget) -> Option<&Track> {
+        let current = self
+            .followed
+            .filter(|&id| self.tracks().any(|t| t.id == id && t.class == class));
+
+        self.followed = current.or_else(|| {
+            self.tracks()
+                .filter(|t| t.class == class)
+                .filter_map(|t| Some((t.id, t.blob.as_ref()?)))
+                .max_by_key(|(_, blob)| target.rank(blob))
+                .map(|(id, _)| id)
+        });
+
+        let id = self.followed?;
+        self.tracks().find(|t| t.id == id)
+    }
+
+    pub fn print(&self, frame: &Frame) {
+        for track in self.tracks() {
+            let (x, y) = track.position();
+            let (vx, vy) = track.velocity();
+
+            println!(
+                "Track {}{}: {} at ({:.0}, {:.0}) moving ({:.0}, {:.0}) pixels/s{}",
+                track.id,
+                if self.followed == Some(track.id) { " (followed)" } else { "" },
+                frame.class(track.class).map_or("?", |c| c.name.as_str()),
+                x,
+                y,
+                vx,
+                vy,
+                if track.blob.is_none() { ", not seen" } else { "" }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn blob(centroid: (usize, usize)) -> Blob {
+        Blob {
+            class: 0,
+            area: 100,
+            bounding_box: (
+                (centroid.0 - 5, centroid.1 - 5),
+                (centroid.0 + 4, centroid.1 + 4),
+            ),
+            centroid,
+            outline: Default::default(),
+        }
+    }
+
+    // Feeds the tracker one frame every 1/30 s
+    fn step(tracker: &mut Tracker, blobs: &[Blob], frame: &mut u32, start: Instant) {
+        *frame += 1;
+        tracker.match_blobs(blobs, start + Duration::from_millis(u64::from(*frame) * 33));
+    }
+
+    fn ids(tracker: &Tracker) -> Vec<u32> {
+        tracker.tracks().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn tracks_are_confirmed_after_min_hits() {
+        let settings = TrackerSettings::default();
+        let mut tracker = Tracker::new(settings);
+        let (start, mut frame) = (Instant::now(), 0);
+
+        for _ in 1..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100))], &mut frame, start);
+            assert!(ids(&tracker).is_empty());
+        }
+
+        step(&mut tracker, &[blob((102, 100))], &mut frame, start);
+        assert_eq!(ids(&tracker), vec![1]);
+    }
+
+    #[test]
+    fn ids_survive_up_to_max_misses() {
+        let settings = TrackerSettings::default();
+        let mut tracker = Tracker::new(settings);
+        let (start, mut frame) = (Instant::now(), 0);
+
+        for _ in 0..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100))], &mut frame, start);
+        }
+
+        for _ in 0..settings.max_misses {
+            step(&mut tracker, &[], &mut frame, start);
+            assert_eq!(ids(&tracker), vec![1]);
+        }
+
+        step(&mut tracker, &[blob((101, 100))], &mut frame, start);
+        assert_eq!(ids(&tracker), vec![1]);
+        assert!(tracker.tracks().all(|t| t.blob.is_some()));
+    }
+
+    #[test]
+    fn tracks_are_dropped_after_max_misses() {
+        let settings = TrackerSettings::default();
+        let mut tracker = Tracker::new(settings);
+        let (start, mut frame) = (Instant::now(), 0);
+
+        for _ in 0..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100))], &mut frame, start);
+        }
+
+        for _ in 0..=settings.max_misses {
+            step(&mut tracker, &[], &mut frame, start);
+        }
+        assert!(ids(&tracker).is_empty());
+
+        for _ in 0..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100))], &mut frame, start);
+        }
+        assert_eq!(ids(&tracker), vec![2]);
+    }
+
+    #[test]
+    fn blobs_outside_the_gate_start_new_tracks() {
+        let settings = TrackerSettings::default();
+        let mut tracker = Tracker::new(settings);
+        let (start, mut frame) = (Instant::now(), 0);
+
+        for _ in 0..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100))], &mut frame, start);
+        }
+
+        let far = 100 + settings.gate as usize + 50;
+        for _ in 0..settings.min_hits {
+            step(&mut tracker, &[blob((100, 100)), blob((far, 100))], &mut frame, start);
+        }
+        assert_eq!(ids(&tracker), vec![1, 2]);
+    }
+}